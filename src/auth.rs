@@ -0,0 +1,370 @@
+use chrono::{DateTime, Utc};
+use lunatic::{
+    abstract_process,
+    ap::{Config, ProcessRef},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use submillisecond::{
+    extract::{FromRequest, Query},
+    http::header,
+    response::{IntoResponse, Response},
+    RequestContext,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    storage::{KeysSnapshot, Storage, DEFAULT_WORKSPACE},
+    ApiError,
+};
+
+// =====================================
+// Keys
+// =====================================
+
+/// A freshly created API key. This is the only time the secret is shown,
+/// only its hash is kept.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: u32,
+    pub name: String,
+    /// Admin keys see every pile and manage the other keys.
+    #[serde(default)]
+    pub admin: bool,
+    /// The only workspace the key can be used in. Without one, admin keys can
+    /// be used in every workspace and other keys in the default one.
+    #[serde(default)]
+    pub workspace: Option<String>,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An API key as it is stored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredKey {
+    pub id: u32,
+    pub name: String,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub workspace: Option<String>,
+    /// Hex encoded SHA-256 of the secret.
+    #[serde(default)]
+    pub secret_hash: String,
+    /// Only read, from `keys.json` files that still have plain secrets.
+    #[serde(default, skip_serializing)]
+    pub secret: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An API key without its secret.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyInfo {
+    id: u32,
+    name: String,
+    admin: bool,
+    workspace: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<&StoredKey> for KeyInfo {
+    fn from(key: &StoredKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name.clone(),
+            admin: key.admin,
+            workspace: key.workspace.clone(),
+            created_at: key.created_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateKeyDTO {
+    pub name: String,
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(default)]
+    pub workspace: Option<String>,
+}
+
+/// What a key may do with a pile it was given access to. Every role includes
+/// the ones before it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Read the pile, its tasks and its feed.
+    Viewer,
+    /// Add, edit and reorder tasks.
+    Contributor,
+    /// Claim, complete and reopen tasks, move them elsewhere, undo.
+    Completer,
+    /// Change or delete the pile and hand out roles. Owners always have it.
+    Admin,
+}
+
+/// Who is making a request, as established by `authenticate`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Caller {
+    /// `None` for the key from `ADMIN_API_KEY`, which is not stored.
+    pub key_id: Option<u32>,
+    pub admin: bool,
+    pub workspace: Option<String>,
+}
+
+impl Caller {
+    pub fn may_use(&self, workspace: &str) -> bool {
+        match &self.workspace {
+            Some(own) => own == workspace,
+            None => self.admin || workspace == DEFAULT_WORKSPACE,
+        }
+    }
+
+    /// Admin keys tied to a workspace only administer its piles. Keys and
+    /// workspaces are managed with the others.
+    pub fn is_global_admin(&self) -> bool {
+        self.admin && self.workspace.is_none()
+    }
+
+    /// Whether the caller may see a pile owned by `owner`. Piles from before
+    /// there were keys have no owner and are left to the admins.
+    pub fn owns(&self, owner: Option<u32>) -> bool {
+        self.admin || (owner.is_some() && owner == self.key_id)
+    }
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("the host has no source of randomness");
+    format!("sk_{}", hex(&bytes))
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex(&Sha256::digest(secret.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// =====================================
+// Key store process
+// =====================================
+pub struct KeyStore {
+    storage: Storage,
+    next_id: u32,
+    keys: Vec<StoredKey>,
+    // of the key from `ADMIN_API_KEY`, which only lives in the environment
+    admin_hash: Option<String>,
+}
+
+impl KeyStore {
+    fn snapshot(&self) -> KeysSnapshot {
+        KeysSnapshot {
+            next_id: self.next_id,
+            keys: self.keys.clone(),
+        }
+    }
+
+    fn add_key(&mut self, dto: CreateKeyDTO) -> Result<ApiKey, ApiError> {
+        let key = ApiKey {
+            id: self.next_id,
+            name: dto.name,
+            admin: dto.admin,
+            workspace: dto.workspace,
+            secret: generate_secret(),
+            created_at: Utc::now(),
+        };
+        let mut snapshot = self.snapshot();
+        snapshot.next_id += 1;
+        snapshot.keys.push(StoredKey {
+            id: key.id,
+            name: key.name.clone(),
+            admin: key.admin,
+            workspace: key.workspace.clone(),
+            secret_hash: hash_secret(&key.secret),
+            secret: None,
+            created_at: key.created_at,
+        });
+        // keys change rarely, a full snapshot per change is plenty
+        self.storage
+            .save_keys(&snapshot)
+            .map_err(|err| ApiError::Storage(err.to_string()))?;
+        self.next_id = snapshot.next_id;
+        self.keys = snapshot.keys;
+        Ok(key)
+    }
+}
+
+#[abstract_process(visibility = pub)]
+impl KeyStore {
+    #[init]
+    fn init(
+        _: Config<Self>,
+        (storage, admin_secret): (Storage, Option<String>),
+    ) -> Result<Self, ()> {
+        let snapshot = storage
+            .load_keys()
+            .map_err(|err| eprintln!("Failed to load API keys: {err}"))?
+            .unwrap_or_default();
+        let mut store = Self {
+            storage,
+            next_id: snapshot.next_id,
+            keys: snapshot.keys,
+            admin_hash: admin_secret.as_deref().map(hash_secret),
+        };
+        let mut migrated = false;
+        for key in &mut store.keys {
+            if let Some(secret) = key.secret.take() {
+                key.secret_hash = hash_secret(&secret);
+                migrated = true;
+            }
+        }
+        if migrated {
+            store
+                .storage
+                .save_keys(&store.snapshot())
+                .map_err(|err| eprintln!("Failed to hash stored API keys: {err}"))?;
+        }
+        // without any key nobody could get in to create the first one
+        if store.keys.is_empty() && store.admin_hash.is_none() {
+            eprintln!("No API keys yet, set ADMIN_API_KEY to create the first ones");
+            return Err(());
+        }
+        Ok(store)
+    }
+
+    #[handle_request]
+    fn authenticate(&self, secret: String) -> Option<Caller> {
+        let hash = hash_secret(&secret);
+        // the time a comparison takes must not tell how much of a guess was right
+        let matches = |stored: &str| bool::from(stored.as_bytes().ct_eq(hash.as_bytes()));
+        if self.admin_hash.as_deref().is_some_and(matches) {
+            return Some(Caller {
+                key_id: None,
+                admin: true,
+                workspace: None,
+            });
+        }
+        self.keys
+            .iter()
+            .find(|key| matches(&key.secret_hash))
+            .map(|key| Caller {
+                key_id: Some(key.id),
+                admin: key.admin,
+                workspace: key.workspace.clone(),
+            })
+    }
+
+    #[handle_request]
+    fn create_key(&mut self, dto: CreateKeyDTO) -> Result<ApiKey, ApiError> {
+        self.add_key(dto)
+    }
+
+    #[handle_request]
+    fn list_keys(&self) -> Vec<KeyInfo> {
+        self.keys.iter().map(KeyInfo::from).collect()
+    }
+
+    #[handle_request]
+    fn revoke_key(&mut self, key_id: u32) -> Result<(), ApiError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == key_id)
+            .ok_or(ApiError::KeyNotFound(key_id))?;
+        // nobody could manage the keys any more, short of a restart with
+        // `ADMIN_API_KEY`
+        let global_admin = |key: &StoredKey| key.admin && key.workspace.is_none();
+        let last_admin = global_admin(key)
+            && self.admin_hash.is_none()
+            && !self
+                .keys
+                .iter()
+                .any(|other| global_admin(other) && other.id != key_id);
+        if last_admin {
+            return Err(ApiError::Conflict(
+                "the last admin key cannot be revoked".to_string(),
+            ));
+        }
+        let mut snapshot = self.snapshot();
+        snapshot.keys.retain(|key| key.id != key_id);
+        self.storage
+            .save_keys(&snapshot)
+            .map_err(|err| ApiError::Storage(err.to_string()))?;
+        self.keys = snapshot.keys;
+        Ok(())
+    }
+}
+
+pub fn key_store() -> Result<ProcessRef<KeyStore>, ApiError> {
+    ProcessRef::<KeyStore>::lookup(&"keys").ok_or(ApiError::KeyStoreUnavailable)
+}
+
+// =====================================
+// Middleware
+// =====================================
+
+/// Turns away requests without a valid key. The key goes into an
+/// `Authorization: Bearer <key>` or `X-Api-Key` header. Websockets, event
+/// streams and calendar feeds also take it as `?api_key=`, since browsers and
+/// calendar apps cannot set headers on those.
+pub fn authenticate(mut req: RequestContext) -> Response {
+    if req.uri().path() == "/api/alive" {
+        return req.next_handler();
+    }
+    match caller_of(&mut req) {
+        Ok(caller) => {
+            req.extensions_mut().insert(caller);
+            req.next_handler()
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+fn caller_of(req: &mut RequestContext) -> Result<Caller, ApiError> {
+    let secret = presented_key(req).ok_or(ApiError::Unauthorized)?;
+    key_store()?
+        .authenticate(secret)
+        .ok_or(ApiError::Unauthorized)
+}
+
+#[derive(Deserialize)]
+struct KeyQuery {
+    api_key: Option<String>,
+}
+
+fn presented_key(req: &mut RequestContext) -> Option<String> {
+    let headers = req.headers();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let api_key = headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok());
+    if let Some(key) = bearer.or(api_key) {
+        return Some(key.trim().to_string());
+    }
+    // anywhere else a key in the URL would only end up in logs and histories
+    if !takes_key_in_query(req.uri().path()) {
+        return None;
+    }
+    let Query(query) = Query::<KeyQuery>::from_request(req).ok()?;
+    query.api_key.map(|key| key.trim().to_string())
+}
+
+fn takes_key_in_query(path: &str) -> bool {
+    path.ends_with("/ws") || path.ends_with("/events") || path.ends_with(".ics")
+}
+
+impl FromRequest for Caller {
+    type Rejection = ApiError;
+
+    fn from_request(req: &mut RequestContext) -> Result<Self, Self::Rejection> {
+        req.extensions()
+            .get::<Caller>()
+            .cloned()
+            .ok_or(ApiError::Unauthorized)
+    }
+}
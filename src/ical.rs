@@ -0,0 +1,204 @@
+//! Due tasks as an iCalendar feed ([RFC 5545](https://www.rfc-editor.org/rfc/rfc5545)).
+//!
+//! Calendar apps cannot send headers, so subscriptions pass the key as
+//! `?api_key=`. Only open tasks with a due date are in the feed, a task
+//! drops out of it once it is completed.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{is_midnight, PileInfo, Task, TaskStatus};
+
+/// What a task turns into. Most calendar apps only show events, to-dos end
+/// up in task lists, if anywhere.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Component {
+    /// An all-day event on the due date, or a point in time if the task is
+    /// due at a specific time.
+    #[default]
+    VEvent,
+    VTodo,
+}
+
+pub fn calendar(
+    name: &str,
+    workspace: &str,
+    component: Component,
+    piles: &[(PileInfo, Vec<Task>)],
+) -> String {
+    let now = Utc::now();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//stack-todo-app//piles//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(name)),
+    ];
+    for (pile, tasks) in piles {
+        // tasks whose status was set to done by hand are no longer due either
+        let due_tasks = tasks
+            .iter()
+            .filter(|task| matches!(task.status, TaskStatus::Open | TaskStatus::InProgress))
+            .filter_map(|task| Some((task, task.due_at?)));
+        for (task, due_at) in due_tasks {
+            let kind = match component {
+                Component::VEvent => "VEVENT",
+                Component::VTodo => "VTODO",
+            };
+            lines.push(format!("BEGIN:{kind}"));
+            // ids are only unique within a pile, and pile ids within a
+            // workspace
+            lines.push(format!(
+                "UID:{}-{}-{}@stack-todo-app",
+                workspace, pile.id, task.id
+            ));
+            lines.push(format!("DTSTAMP:{}", timestamp(now)));
+            if let Some(created_at) = task.created_at {
+                lines.push(format!("CREATED:{}", timestamp(created_at)));
+            }
+            if let Some(updated_at) = task.updated_at {
+                lines.push(format!("LAST-MODIFIED:{}", timestamp(updated_at)));
+            }
+            lines.push(due(component, due_at));
+            lines.push(format!("SUMMARY:{}", escape(&task.title)));
+            if !task.description.is_empty() {
+                lines.push(format!("DESCRIPTION:{}", escape(&task.description)));
+            }
+            let categories: Vec<String> = std::iter::once(&pile.name)
+                .chain(&task.tags)
+                .map(|category| escape(category))
+                .collect();
+            lines.push(format!("CATEGORIES:{}", categories.join(",")));
+            // 1 is the most urgent in iCalendar, 0 means undefined
+            if let Some(priority) = task.priority {
+                lines.push(format!("PRIORITY:{}", priority.saturating_add(1).min(9)));
+            }
+            if component == Component::VTodo {
+                let status = match task.status {
+                    TaskStatus::InProgress => "IN-PROCESS",
+                    _ => "NEEDS-ACTION",
+                };
+                lines.push(format!("STATUS:{status}"));
+            }
+            lines.push(format!("END:{kind}"));
+        }
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut ics = String::new();
+    for line in lines {
+        fold(&mut ics, &line);
+    }
+    ics
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// a due date without a time is all day, anything else a point in time
+fn due(component: Component, due_at: DateTime<Utc>) -> String {
+    let name = match component {
+        Component::VEvent => "DTSTART",
+        Component::VTodo => "DUE",
+    };
+    if is_midnight(due_at) {
+        format!("{name};VALUE=DATE:{}", due_at.format("%Y%m%d"))
+    } else {
+        format!("{name}:{}", timestamp(due_at))
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// lines may be at most 75 octets long, longer ones continue on the next
+// line after a space, never in the middle of a character
+fn fold(ics: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            ics.push_str("\r\n ");
+            width = 1;
+        }
+        ics.push(c);
+        width += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn folded(line: &str) -> String {
+        let mut ics = String::new();
+        fold(&mut ics, line);
+        ics
+    }
+
+    #[test]
+    fn leaves_short_lines_alone() {
+        let line = "x".repeat(75);
+        assert_eq!(folded(&line), format!("{line}\r\n"));
+    }
+
+    #[test]
+    fn folds_after_75_octets() {
+        let line = "x".repeat(150);
+        let ics = folded(&line);
+        let lines: Vec<&str> = ics.split("\r\n").collect();
+        let continued = format!(" {}", &line[75..149]);
+        assert_eq!(lines, [&line[..75], continued.as_str(), " x", ""]);
+    }
+
+    #[test]
+    fn never_folds_inside_a_character() {
+        // 74 octets and a 2 octet character do not fit into 75
+        let line = format!("{}é{}", "x".repeat(74), "ü".repeat(40));
+        let ics = folded(&line);
+        let lines: Vec<&str> = ics.split("\r\n").collect();
+        assert_eq!(lines[0], "x".repeat(74));
+        assert!(lines[1].starts_with(" é"));
+        for part in &lines {
+            assert!(part.len() <= 75, "{part:?} is {} octets", part.len());
+        }
+        // unfolding gives back the line
+        assert_eq!(ics.replace("\r\n ", ""), format!("{line}\r\n"));
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(escape("a,b;c\\d\r\ne"), r"a\,b\;c\\d\ne");
+    }
+
+    #[test]
+    fn tasks_due_at_midnight_are_due_all_day() {
+        let day = Utc.with_ymd_and_hms(2024, 3, 5, 0, 0, 0).unwrap();
+        assert_eq!(due(Component::VEvent, day), "DTSTART;VALUE=DATE:20240305");
+        assert_eq!(due(Component::VTodo, day), "DUE;VALUE=DATE:20240305");
+    }
+
+    #[test]
+    fn tasks_due_at_a_time_are_due_then() {
+        let at = Utc.with_ymd_and_hms(2024, 3, 5, 14, 30, 0).unwrap();
+        assert_eq!(due(Component::VEvent, at), "DTSTART:20240305T143000Z");
+        assert_eq!(due(Component::VTodo, at), "DUE:20240305T143000Z");
+    }
+}
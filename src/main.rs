@@ -1,21 +1,84 @@
-use std::collections::{HashMap, VecDeque};
+mod auth;
+mod ical;
+mod markdown;
+mod ordering;
+mod storage;
+mod todotxt;
+mod wal;
 
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Timelike, Utc};
 use lunatic::{
     abstract_process,
-    ap::{Config, ProcessRef},
+    ap::{Config, DeferredResponse, ProcessRef},
+    supervisor::{Supervisor, SupervisorConfig, SupervisorStrategy},
     AbstractProcess, Tag,
 };
-use serde::{Deserialize, Serialize};
-use submillisecond::{router, Application, Json, Router};
+use serde::{Deserialize, Deserializer, Serialize};
+use submillisecond::{
+    extract::{FromRequest, Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    router,
+    websocket::{Message, WebSocket, WebSocketConnection, WebSocketUpgrade},
+    Application, Json, RequestContext, Router,
+};
+
+use auth::{authenticate, key_store, ApiKey, Caller, CreateKeyDTO, KeyInfo, KeyStore, Role};
+use ordering::{deserialize_policy, OrderingPolicy, TaskOrdering};
+use storage::{PileSnapshot, RegistrySnapshot, Storage, DEFAULT_WORKSPACE};
+use wal::WriteAheadLog;
 
 // =====================================
 // DTOs
 // =====================================
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskStatus {
+    #[default]
+    Open,
+    InProgress,
+    Done,
+    Cancelled,
+}
+
+// everything past `description` is optional so tasks stored before these
+// fields existed still load
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Task {
     id: u32,
     title: String,
     description: String,
+    #[serde(default)]
+    status: TaskStatus,
+    /// 0 is the most urgent, like P0.
+    #[serde(default)]
+    priority: Option<u8>,
+    #[serde(default)]
+    due_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    completed_at: Option<DateTime<Utc>>,
+    /// How often the task was handed out and came back unfinished.
+    #[serde(default)]
+    attempts: u32,
+    #[serde(default)]
+    failures: Vec<TaskFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TaskFailure {
+    at: DateTime<Utc>,
+    reason: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -23,30 +86,1066 @@ pub struct PileInfo {
     id: u32,
     name: String,
     description: String,
-    is_stack: bool,
+    #[serde(alias = "is_stack", deserialize_with = "deserialize_policy")]
+    policy: OrderingPolicy,
+    /// How many operations can be undone.
+    #[serde(default = "default_history_depth")]
+    history_depth: usize,
+    /// After this many failed attempts a task is taken out of the pile.
+    #[serde(default)]
+    max_attempts: Option<u32>,
+    /// Pile that receives tasks that ran out of attempts. Without one they are
+    /// archived as cancelled.
+    #[serde(default)]
+    dead_letter: Option<u32>,
+    /// Pile that completed tasks move on to. A completed copy stays archived
+    /// here.
+    #[serde(default)]
+    on_complete: Option<CompletionTarget>,
+    /// Key that created the pile.
+    #[serde(default)]
+    owner: Option<u32>,
+    /// Roles of other keys, by key id.
+    #[serde(default)]
+    acl: BTreeMap<u32, Role>,
+    /// Bumped on every update, so a restarted pile can tell which of the
+    /// copies it knows about is the latest.
+    #[serde(default)]
+    revision: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CreatePileDTO {
     name: String,
     description: String,
-    is_stack: bool,
+    #[serde(default, alias = "is_stack", deserialize_with = "deserialize_policy")]
+    policy: OrderingPolicy,
+    #[serde(default)]
+    history_depth: Option<usize>,
+    #[serde(default)]
+    max_attempts: Option<u32>,
+    #[serde(default)]
+    dead_letter: Option<u32>,
+    #[serde(default)]
+    on_complete: Option<CompletionTarget>,
+}
+
+/// The next stage of a pipeline.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompletionTarget {
+    pile_id: u32,
+    /// Status the task arrives with, defaults to open.
+    #[serde(default)]
+    status: Option<TaskStatus>,
+}
+
+const MAX_HISTORY_DEPTH: usize = 100;
+
+impl PileInfo {
+    /// What `caller` may do with the pile, `None` if it may not even see it.
+    fn role_of(&self, caller: &Caller) -> Option<Role> {
+        if caller.owns(self.owner) {
+            return Some(Role::Admin);
+        }
+        caller
+            .key_id
+            .and_then(|key_id| self.acl.get(&key_id).copied())
+    }
+
+    /// The info as a caller with `role` gets to see it. Who else has access
+    /// is only shown to those who may change it.
+    fn shown_to(mut self, role: Role) -> Self {
+        if role < Role::Admin {
+            self.acl.clear();
+        }
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GrantDTO {
+    role: Role,
+}
+
+fn default_history_depth() -> usize {
+    20
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdatePileDTO {
+    name: Option<String>,
+    description: Option<String>,
+    #[serde(default, deserialize_with = "double_option")]
+    max_attempts: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "double_option")]
+    dead_letter: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "double_option")]
+    on_complete: Option<Option<CompletionTarget>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateTaskDTO {
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    priority: Option<u8>,
+    #[serde(default)]
+    due_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InsertTaskDTO {
+    /// 0 puts the task on top.
+    depth: usize,
+    task: CreateTaskDTO,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MoveTaskDTO {
+    depth: usize,
+}
+
+/// A task handed to a consumer, hidden from the pile until it is acked,
+/// nacked or the lease runs out.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Lease {
+    token: u64,
+    consumer: String,
+    expires_at: DateTime<Utc>,
+    // where the task was taken from, it goes back there if the lease ends
+    index: usize,
+    task: Task,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClaimDTO {
+    consumer: String,
+    /// e.g. `30s`, defaults to 30 seconds
+    visibility_timeout: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeaseTokenDTO {
+    token: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NackDTO {
+    token: u64,
+    reason: Option<String>,
+}
+
+/// A task on its way to another pile. It stays in the sender's outbox until
+/// the receiver confirms it, and the receiver ignores repeated deliveries.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Handoff {
+    id: u64,
+    target: u32,
+    task: Task,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MoveToPileDTO {
+    target: u32,
+    /// Defaults to the task on top.
+    task_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExtendLeaseDTO {
+    token: u64,
+    visibility_timeout: Option<String>,
+}
+
+// a field that is missing is left alone, `null` clears it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateTaskDTO {
+    title: Option<String>,
+    description: Option<String>,
+    status: Option<TaskStatus>,
+    #[serde(default, deserialize_with = "double_option")]
+    priority: Option<Option<u8>>,
+    #[serde(default, deserialize_with = "double_option")]
+    due_at: Option<Option<DateTime<Utc>>>,
+    tags: Option<Vec<String>>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TaskFilter {
+    status: Option<TaskStatus>,
+    tag: Option<String>,
+    /// Only tasks at least this urgent, i.e. with a priority number <= this.
+    priority: Option<u8>,
+    due_before: Option<DateTime<Utc>>,
+}
+
+impl TaskFilter {
+    fn matches(&self, task: &Task) -> bool {
+        self.status.is_none_or(|status| task.status == status)
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| task.tags.iter().any(|t| t == tag))
+            && self
+                .priority
+                .is_none_or(|max| task.priority.is_some_and(|p| p <= max))
+            && self
+                .due_before
+                .is_none_or(|before| task.due_at.is_some_and(|due| due < before))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Page<T> {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    items: Vec<T>,
+}
+
+const MAX_PAGE_LIMIT: usize = 500;
+
+fn default_page_limit() -> usize {
+    50
+}
+
+// Lets JSON bodies tell a missing field from an explicit `null`. Messages
+// between processes are bincode, which encodes the nesting itself.
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    if !deserializer.is_human_readable() {
+        return Option::<Option<T>>::deserialize(deserializer);
+    }
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Whether `at` is the very start of a day, i.e. stands for a date rather
+/// than a point in time.
+fn is_midnight(at: DateTime<Utc>) -> bool {
+    at.num_seconds_from_midnight() == 0 && at.nanosecond() == 0
+}
+
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_string();
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+// =====================================
+// Events
+// =====================================
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PileEventKind {
+    PileCreated,
+    TaskPushed,
+    TaskUpdated,
+    TaskCompleted,
+    TaskReopened,
+    TaskClaimed,
+    TaskReleased,
+    TaskDeadLettered,
+    TaskAbandoned,
+    /// The task left for another pile.
+    TaskMoved,
+    Reordered,
+    /// An undo or redo changed the pile, clients should reload the tasks.
+    Reverted,
+    /// Tasks were added in bulk, clients should reload the tasks.
+    Imported,
+    PileDeleted,
+}
+
+impl PileEventKind {
+    // same as the serialized form
+    fn name(&self) -> &'static str {
+        match self {
+            PileEventKind::PileCreated => "pile_created",
+            PileEventKind::TaskPushed => "task_pushed",
+            PileEventKind::TaskUpdated => "task_updated",
+            PileEventKind::TaskCompleted => "task_completed",
+            PileEventKind::TaskReopened => "task_reopened",
+            PileEventKind::TaskClaimed => "task_claimed",
+            PileEventKind::TaskReleased => "task_released",
+            PileEventKind::TaskDeadLettered => "task_dead_lettered",
+            PileEventKind::TaskAbandoned => "task_abandoned",
+            PileEventKind::TaskMoved => "task_moved",
+            PileEventKind::Reordered => "reordered",
+            PileEventKind::Reverted => "reverted",
+            PileEventKind::Imported => "imported",
+            PileEventKind::PileDeleted => "pile_deleted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PileEvent {
+    pile_id: u32,
+    kind: PileEventKind,
+    task: Option<Task>,
+    at: DateTime<Utc>,
+}
+
+/// A `PileEvent` as kept in the registry's activity buffer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ActivityEvent {
+    id: u64,
+    event: PileEvent,
+    // owner of the pile, decides who gets to see the event
+    owner: Option<u32>,
+}
+
+// =====================================
+// Log records
+// =====================================
+
+// Operations are logged by their effect rather than by the request that caused
+// them, so that replaying them never depends on anything but the log itself.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum PileOp {
+    Push(Task),
+    Update(Task),
+    Complete {
+        task_id: u32,
+        at: DateTime<Utc>,
+    },
+    Reopen {
+        task_id: u32,
+        at: DateTime<Utc>,
+    },
+    Insert {
+        task: Task,
+        index: usize,
+    },
+    Move {
+        task_id: u32,
+        index: usize,
+    },
+    Claim(Lease),
+    Extend {
+        task_id: u32,
+        expires_at: DateTime<Utc>,
+    },
+    /// Puts a leased task back where it was claimed from. Only logs from
+    /// before failed attempts were counted have it, `Fail` does it now.
+    Release {
+        task_id: u32,
+    },
+    /// Counts a failed attempt of a leased task and settles where it goes.
+    Fail {
+        task_id: u32,
+        failure: TaskFailure,
+        outcome: FailureOutcome,
+    },
+    Configure(PileInfo),
+    /// Moves a task into the outbox.
+    HandOff(Handoff),
+    /// Archives a completed copy of a task and moves the task into the outbox,
+    /// on to the next stage.
+    PassOn {
+        handoff: Handoff,
+        at: DateTime<Utc>,
+    },
+    Delivered {
+        handoff_id: u64,
+    },
+    /// Puts the task of a handoff whose target pile is gone back into the
+    /// pile.
+    Return {
+        handoff_id: u64,
+    },
+    Receive {
+        source: u32,
+        handoff_id: u64,
+        task: Task,
+        // the sender has no handoffs with lower ids left
+        confirmed_below: u64,
+    },
+    /// Adds tasks as they are, ids included, e.g. from an export.
+    Load {
+        tasks: Vec<Task>,
+        done: Vec<Task>,
+    },
+    // the ones below are only produced as the inverse of another operation
+    Discard {
+        task_id: u32,
+    },
+    Restore {
+        task: Task,
+        index: usize,
+    },
+    Archive {
+        task: Task,
+        index: usize,
+    },
+}
+
+/// What becomes of a leased task that failed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum FailureOutcome {
+    /// Back where it was claimed from, for another attempt.
+    Retry,
+    /// Out of attempts, on its way to the dead-letter pile.
+    DeadLetter { handoff_id: u64, target: u32 },
+    /// Out of attempts and archived as cancelled.
+    Abandon,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RegistryOp {
+    Created(PileInfo),
+    Updated(PileInfo),
+    Deleted { pile_id: u32 },
+}
+
+// number of logged operations after which the log is folded into a snapshot
+const COMPACT_AFTER: u64 = 256;
+
+// number of completed tasks a pile keeps, older ones are dropped from the
+// archive. Exports and the done list only go back this far.
+const ARCHIVE_LIMIT: usize = 1000;
+
+// how long to wait for a receiving pile to confirm a handoff before resending
+const HANDOFF_RETRY: Duration = Duration::from_secs(5);
+
+// =====================================
+// Pile process
+// =====================================
+// a worker waiting in `wait_for_task`
+struct TaskWaiter {
+    deadline: Instant,
+    response: DeferredResponse<Option<Task>, Pile>,
+}
+
 pub struct Pile {
+    this: ProcessRef<Pile>,
     info: PileInfo,
+    next_task_id: u32,
     tasks: VecDeque<Task>,
+    // completed tasks, oldest first, at most `ARCHIVE_LIMIT` of them
+    done: Vec<Task>,
+    // claimed tasks by task id
+    leases: HashMap<u32, Lease>,
+    next_lease_token: u64,
+    // tasks waiting for another pile to confirm them, by handoff id
+    outbox: BTreeMap<u64, Handoff>,
+    next_handoff_id: u64,
+    handoff_retry_scheduled: bool,
+    // handoff ids received so far, by sending pile
+    received: HashMap<u32, BTreeSet<u64>>,
+    // inverses of the latest operations, the most recent one last. Like the
+    // redo stack this only lives in memory and starts out empty after a restart.
+    history: VecDeque<PileOp>,
+    redo: Vec<PileOp>,
+    task_waiters: Vec<TaskWaiter>,
+    // tasks handed to waiters that are still in the pile, the other waiters
+    // wait for tasks of their own
+    offered_tasks: HashSet<u32>,
+    storage: Storage,
+    log: WriteAheadLog<PileOp>,
+    pending_ops: u64,
+}
+
+impl Pile {
+    /// Writes `op` to the log and only then applies it, so nothing gets
+    /// acknowledged that a crash could take back. Returns the inverse of `op`.
+    fn commit(&mut self, op: PileOp) -> Result<Option<PileOp>, ApiError> {
+        self.log
+            .append(&op)
+            .map_err(|err| ApiError::Storage(err.to_string()))?;
+        let inverse = self.apply(op);
+        self.trim_archive();
+        self.serve_task_waiters();
+        self.pending_ops += 1;
+        if self.pending_ops >= COMPACT_AFTER {
+            // the operation is safe in the log already, compaction can be retried later
+            if let Err(err) = self.compact() {
+                eprintln!("Failed to compact pile {}: {err}", self.info.id);
+            }
+        }
+        Ok(inverse)
+    }
+
+    fn trim_archive(&mut self) {
+        let excess = self.done.len().saturating_sub(ARCHIVE_LIMIT);
+        self.done.drain(..excess);
+    }
+
+    /// Commits an operation a user asked for, so that it can be undone.
+    fn record(&mut self, op: PileOp) -> Result<(), ApiError> {
+        if let Some(inverse) = self.commit(op)? {
+            self.remember(inverse);
+            self.redo.clear();
+        }
+        Ok(())
+    }
+
+    fn remember(&mut self, inverse: PileOp) {
+        self.history.push_back(inverse);
+        while self.history.len() > self.info.history_depth {
+            self.history.pop_front();
+        }
+    }
+
+    /// Applies `op` and returns the operation that reverts it, or `None` if
+    /// `op` did not change anything.
+    fn apply(&mut self, op: PileOp) -> Option<PileOp> {
+        match op {
+            PileOp::Push(task) => {
+                let task_id = task.id;
+                self.next_task_id = self.next_task_id.max(task_id + 1);
+                self.tasks.push_back(task);
+                Some(PileOp::Discard { task_id })
+            }
+            PileOp::Insert { task, index } => {
+                let task_id = task.id;
+                self.next_task_id = self.next_task_id.max(task_id + 1);
+                self.tasks.insert(index.min(self.tasks.len()), task);
+                Some(PileOp::Discard { task_id })
+            }
+            PileOp::Move { task_id, index } => {
+                let from = self.task_index(task_id)?;
+                let task = self.tasks.remove(from)?;
+                self.tasks.insert(index.min(self.tasks.len()), task);
+                Some(PileOp::Move {
+                    task_id,
+                    index: from,
+                })
+            }
+            PileOp::Claim(lease) => {
+                let index = self.task_index(lease.task.id)?;
+                self.tasks.remove(index)?;
+                self.next_lease_token = self.next_lease_token.max(lease.token + 1);
+                self.leases.insert(lease.task.id, Lease { index, ..lease });
+                // claims are not undone, a consumer is working on the task
+                None
+            }
+            PileOp::Extend {
+                task_id,
+                expires_at,
+            } => {
+                self.leases.get_mut(&task_id)?.expires_at = expires_at;
+                None
+            }
+            PileOp::Release { task_id } => {
+                let lease = self.leases.remove(&task_id)?;
+                self.tasks
+                    .insert(lease.index.min(self.tasks.len()), lease.task);
+                None
+            }
+            PileOp::Fail {
+                task_id,
+                failure,
+                outcome,
+            } => {
+                let at = failure.at;
+                let mut lease = self.leases.remove(&task_id)?;
+                lease.task.attempts += 1;
+                lease.task.updated_at = Some(at);
+                lease.task.failures.push(failure);
+                match outcome {
+                    FailureOutcome::Retry => {
+                        self.tasks
+                            .insert(lease.index.min(self.tasks.len()), lease.task);
+                    }
+                    FailureOutcome::DeadLetter { handoff_id, target } => {
+                        self.next_handoff_id = self.next_handoff_id.max(handoff_id + 1);
+                        let handoff = Handoff {
+                            id: handoff_id,
+                            target,
+                            task: lease.task,
+                        };
+                        self.outbox.insert(handoff_id, handoff);
+                    }
+                    FailureOutcome::Abandon => {
+                        let mut task = lease.task;
+                        task.status = TaskStatus::Cancelled;
+                        task.completed_at = Some(at);
+                        self.done.push(task);
+                    }
+                }
+                None
+            }
+            PileOp::Load { tasks, done } => {
+                for task in tasks.iter().chain(&done) {
+                    self.next_task_id = self.next_task_id.max(task.id.saturating_add(1));
+                }
+                self.tasks.extend(tasks);
+                self.done.extend(done);
+                None
+            }
+            PileOp::Configure(info) => {
+                if info.revision >= self.info.revision {
+                    self.info = info;
+                }
+                None
+            }
+            PileOp::HandOff(handoff) => {
+                let task_id = handoff.task.id;
+                match self.task_index(task_id) {
+                    Some(index) => {
+                        self.tasks.remove(index);
+                    }
+                    None => {
+                        self.leases.remove(&task_id)?;
+                    }
+                }
+                self.next_handoff_id = self.next_handoff_id.max(handoff.id + 1);
+                self.outbox.insert(handoff.id, handoff);
+                None
+            }
+            PileOp::PassOn { handoff, at } => {
+                let task_id = handoff.task.id;
+                let mut completed = match self.task_index(task_id) {
+                    Some(index) => self.tasks.remove(index)?,
+                    None => self.leases.remove(&task_id)?.task,
+                };
+                completed.status = TaskStatus::Done;
+                completed.completed_at = Some(at);
+                completed.updated_at = Some(at);
+                self.done.push(completed);
+                self.next_handoff_id = self.next_handoff_id.max(handoff.id + 1);
+                self.outbox.insert(handoff.id, handoff);
+                None
+            }
+            PileOp::Delivered { handoff_id } => {
+                self.outbox.remove(&handoff_id);
+                None
+            }
+            PileOp::Return { handoff_id } => {
+                let handoff = self.outbox.remove(&handoff_id)?;
+                self.tasks.push_back(handoff.task);
+                None
+            }
+            PileOp::Receive {
+                source,
+                handoff_id,
+                mut task,
+                confirmed_below,
+            } => {
+                let seen = self.received.entry(source).or_default();
+                seen.retain(|&id| id >= confirmed_below);
+                if !seen.insert(handoff_id) {
+                    return None;
+                }
+                // ids are only unique within a pile
+                task.id = self.next_task_id;
+                self.next_task_id += 1;
+                self.tasks.push_back(task);
+                None
+            }
+            PileOp::Discard { task_id } => {
+                let index = self.task_index(task_id)?;
+                let task = self.tasks.remove(index)?;
+                Some(PileOp::Insert { task, index })
+            }
+            PileOp::Update(task) => {
+                let current = self.tasks.iter_mut().find(|t| t.id == task.id)?;
+                Some(PileOp::Update(std::mem::replace(current, task)))
+            }
+            PileOp::Complete { task_id, at } => {
+                // acking a lease completes a task that is no longer in the pile
+                let (task, index) = match self.task_index(task_id) {
+                    Some(index) => (self.tasks.remove(index)?, index),
+                    None => {
+                        let lease = self.leases.remove(&task_id)?;
+                        (lease.task, lease.index)
+                    }
+                };
+                let mut completed = task.clone();
+                completed.status = TaskStatus::Done;
+                completed.completed_at = Some(at);
+                completed.updated_at = Some(at);
+                self.done.push(completed);
+                Some(PileOp::Restore { task, index })
+            }
+            PileOp::Reopen { task_id, at } => {
+                let index = self.done.iter().position(|t| t.id == task_id)?;
+                let task = self.done.remove(index);
+                let mut reopened = task.clone();
+                reopened.status = TaskStatus::Open;
+                reopened.completed_at = None;
+                reopened.updated_at = Some(at);
+                self.tasks.push_back(reopened);
+                Some(PileOp::Archive { task, index })
+            }
+            PileOp::Restore { task, index } => {
+                let done_index = self.done.iter().position(|t| t.id == task.id)?;
+                let archived = self.done.remove(done_index);
+                self.tasks.insert(index.min(self.tasks.len()), task);
+                Some(PileOp::Archive {
+                    task: archived,
+                    index: done_index,
+                })
+            }
+            PileOp::Archive { task, index } => {
+                let task_index = self.task_index(task.id)?;
+                let current = self.tasks.remove(task_index)?;
+                self.done.insert(index.min(self.done.len()), task);
+                Some(PileOp::Restore {
+                    task: current,
+                    index: task_index,
+                })
+            }
+        }
+    }
+
+    // one task per waiter, the one waiting longest gets the task that is
+    // handed out first. Waiters left without a task stay parked.
+    fn serve_task_waiters(&mut self) {
+        if self.task_waiters.is_empty() {
+            return;
+        }
+        let tasks: Vec<Task> = self
+            .info
+            .policy
+            .pop_order(self.info.id, &self.tasks)
+            .into_iter()
+            .map(|index| &self.tasks[index])
+            .filter(|task| !self.offered_tasks.contains(&task.id))
+            .take(self.task_waiters.len())
+            .cloned()
+            .collect();
+        for (waiter, task) in self.task_waiters.drain(..tasks.len()).zip(tasks) {
+            self.offered_tasks.insert(task.id);
+            waiter.response.send_response(Some(task));
+        }
+    }
+
+    fn event(&self, kind: PileEventKind, task: Option<&Task>) -> PileEvent {
+        PileEvent {
+            pile_id: self.info.id,
+            kind,
+            task: task.cloned(),
+            at: Utc::now(),
+        }
+    }
+
+    /// Tells the registry's activity feed, and so every feed, about a change.
+    fn notify(&self, kind: PileEventKind, task: Option<&Task>) {
+        let event = self.event(kind, task);
+        let registry_name = registry_process_name(self.storage.workspace());
+        if let Some(registry) = ProcessRef::<PileRegistry>::lookup(&registry_name) {
+            registry.publish(event);
+        }
+    }
+
+    fn task_index(&self, task_id: u32) -> Option<usize> {
+        self.tasks.iter().position(|task| task.id == task_id)
+    }
+
+    // ids are handed out by the pile, whatever the client thinks they should be
+    fn new_task(&self, dto: CreateTaskDTO) -> Task {
+        let now = Utc::now();
+        Task {
+            id: self.next_task_id,
+            title: dto.title,
+            description: dto.description,
+            priority: dto.priority,
+            due_at: dto.due_at,
+            tags: normalize_tags(dto.tags),
+            created_at: Some(now),
+            updated_at: Some(now),
+            ..Task::default()
+        }
+    }
+
+    fn require_positional(&self) -> Result<(), ApiError> {
+        if !self.info.policy.is_positional() {
+            return Err(ApiError::BadRequest(format!(
+                "tasks of a {:?} pile cannot be reordered by hand",
+                self.info.policy
+            )));
+        }
+        Ok(())
+    }
+
+    /// Moves a task to `depth` below the top.
+    fn move_to_depth(&mut self, task_id: u32, depth: usize) -> Result<Vec<Task>, ApiError> {
+        self.require_positional()?;
+        if self.task_index(task_id).is_none() {
+            return Err(ApiError::TaskNotFound(self.info.id, task_id));
+        }
+        let index = self.info.policy.depth_to_index(depth, self.tasks.len());
+        self.record(PileOp::Move { task_id, index })?;
+        self.notify(PileEventKind::Reordered, None);
+        Ok(self.ordered_tasks())
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        let snapshot = PileSnapshot {
+            seq: self.log.last_seq(),
+            info: Some(self.info.clone()),
+            next_task_id: self.next_task_id,
+            tasks: self.tasks.clone(),
+            done: self.done.clone(),
+            leases: self.leases.values().cloned().collect(),
+            next_lease_token: self.next_lease_token,
+            outbox: self.outbox.values().cloned().collect(),
+            next_handoff_id: self.next_handoff_id,
+            received: self.received.clone(),
+        };
+        self.storage.save_pile(self.info.id, &snapshot)?;
+        self.log.truncate()?;
+        self.pending_ops = 0;
+        Ok(())
+    }
+
+    fn active_lease(&self, task_id: u32, token: u64) -> Result<&Lease, ApiError> {
+        self.leases
+            .get(&task_id)
+            .filter(|lease| lease.token == token)
+            .ok_or_else(|| {
+                ApiError::Conflict(format!(
+                    "task {task_id} has no active lease with token {token}"
+                ))
+            })
+    }
+
+    /// Counts a failed attempt of a leased task. Tasks with attempts left go
+    /// back into the pile, the others to the dead-letter pile.
+    fn fail(&mut self, task_id: u32, reason: String) -> Result<(), ApiError> {
+        let attempts = self.leases[&task_id].task.attempts + 1;
+        let exhausted = self.info.max_attempts.is_some_and(|max| attempts >= max);
+        let outcome = match self.info.dead_letter {
+            _ if !exhausted => FailureOutcome::Retry,
+            Some(target) => FailureOutcome::DeadLetter {
+                handoff_id: self.next_handoff_id,
+                target,
+            },
+            None => FailureOutcome::Abandon,
+        };
+        let failure = TaskFailure {
+            at: Utc::now(),
+            reason,
+        };
+        self.commit(PileOp::Fail {
+            task_id,
+            failure,
+            outcome: outcome.clone(),
+        })?;
+        match outcome {
+            FailureOutcome::Retry => {
+                let task = self.tasks.iter().find(|task| task.id == task_id).cloned();
+                self.notify(PileEventKind::TaskReleased, task.as_ref());
+            }
+            FailureOutcome::DeadLetter { handoff_id, .. } => {
+                let handoff = self.outbox[&handoff_id].clone();
+                self.deliver(&handoff);
+                self.schedule_handoff_retry();
+                self.notify(PileEventKind::TaskDeadLettered, Some(&handoff.task));
+            }
+            FailureOutcome::Abandon => {
+                self.notify(PileEventKind::TaskAbandoned, self.done.last());
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes a task that is in the pile or leased and returns it as it was
+    /// archived. Piles that are part of a pipeline also pass it on to the next
+    /// stage.
+    fn complete(&mut self, task_id: u32) -> Result<Task, ApiError> {
+        let at = Utc::now();
+        let Some(next) = self.info.on_complete.clone() else {
+            self.record(PileOp::Complete { task_id, at })?;
+            let task = self.done.last().cloned().unwrap();
+            self.notify(PileEventKind::TaskCompleted, Some(&task));
+            return Ok(task);
+        };
+        let mut task = match self.tasks.iter().find(|task| task.id == task_id) {
+            Some(task) => task.clone(),
+            None => self.leases[&task_id].task.clone(),
+        };
+        // a new stage, with a fresh set of attempts
+        task.status = next.status.unwrap_or_default();
+        task.attempts = 0;
+        task.updated_at = Some(at);
+        let handoff = Handoff {
+            id: self.next_handoff_id,
+            target: next.pile_id,
+            task,
+        };
+        // handed on rather than recorded, like any move this cannot be undone
+        self.commit(PileOp::PassOn {
+            handoff: handoff.clone(),
+            at,
+        })?;
+        self.schedule_handoff_retry();
+        self.deliver(&handoff);
+        let completed = self.done.last().cloned().unwrap();
+        self.notify(PileEventKind::TaskCompleted, Some(&completed));
+        Ok(completed)
+    }
+
+    /// Moves `task` into the outbox. It is resent until `target` confirms it.
+    fn take_out(&mut self, task: Task, target: u32) -> Result<Handoff, ApiError> {
+        let handoff = Handoff {
+            id: self.next_handoff_id,
+            target,
+            task,
+        };
+        self.commit(PileOp::HandOff(handoff.clone()))?;
+        self.schedule_handoff_retry();
+        Ok(handoff)
+    }
+
+    fn deliver(&self, handoff: &Handoff) {
+        let target_name = pile_process_name(self.storage.workspace(), handoff.target);
+        if let Some(target) = ProcessRef::<Pile>::lookup(&target_name) {
+            target.receive_handoff(self.info.id, handoff.clone(), self.confirmed_below());
+        }
+    }
+
+    // every handoff below the oldest one still pending has been confirmed
+    fn confirmed_below(&self) -> u64 {
+        self.outbox
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(self.next_handoff_id)
+    }
+
+    /// Pushes a task handed off by the `source` pile. Returns `None` if the
+    /// handoff was received before.
+    fn take_in(
+        &mut self,
+        source: u32,
+        handoff: Handoff,
+        confirmed_below: u64,
+    ) -> Result<Option<Task>, ApiError> {
+        let handoff_id = handoff.id;
+        let duplicate = self
+            .received
+            .get(&source)
+            .is_some_and(|seen| seen.contains(&handoff_id));
+        if duplicate {
+            return Ok(None);
+        }
+        self.commit(PileOp::Receive {
+            source,
+            handoff_id,
+            task: handoff.task,
+            confirmed_below,
+        })?;
+        let task = self.tasks.back().cloned();
+        self.notify(PileEventKind::TaskPushed, task.as_ref());
+        Ok(task)
+    }
+
+    fn schedule_handoff_retry(&mut self) {
+        if !self.handoff_retry_scheduled && !self.outbox.is_empty() {
+            self.handoff_retry_scheduled = true;
+            self.this.with_delay(HANDOFF_RETRY).retry_handoffs();
+        }
+    }
+
+    fn schedule_lease_expiry(&self, lease: &Lease) {
+        let remaining = (lease.expires_at - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO);
+        self.this
+            .with_delay(remaining)
+            .expire_lease(lease.task.id, lease.token);
+    }
+
+    fn top(&self) -> Option<&Task> {
+        let index = self.info.policy.next_index(self.info.id, &self.tasks)?;
+        self.tasks.get(index)
+    }
+
+    // in the order complete_current would hand them out
+    fn ordered_tasks(&self) -> Vec<Task> {
+        self.info
+            .policy
+            .pop_order(self.info.id, &self.tasks)
+            .into_iter()
+            .map(|index| self.tasks[index].clone())
+            .collect()
+    }
 }
 
 #[abstract_process(visibility = pub)]
 impl Pile {
     #[init]
-    fn init(_: Config<Self>, info: PileInfo) -> Result<Self, ()> {
-        Ok(Self {
+    fn init(config: Config<Self>, (info, storage): (PileInfo, Storage)) -> Result<Self, ()> {
+        // a pile that was never saved simply starts out empty
+        let snapshot = storage
+            .load_pile(info.id)
+            .map_err(|err| eprintln!("Failed to load pile {}: {err}", info.id))?
+            .unwrap_or_default();
+        let mut log = storage.pile_log(info.id);
+        let records = log
+            .replay(snapshot.seq)
+            .map_err(|err| eprintln!("Failed to replay log of pile {}: {err}", info.id))?;
+
+        // the supervisor restarts us with the info we were first started with,
+        // later updates only made it into our own snapshot
+        let info = match snapshot.info {
+            Some(saved) if saved.revision > info.revision => saved,
+            _ => info,
+        };
+        let mut pile = Self {
+            this: config.self_ref(),
             info,
-            tasks: VecDeque::new(),
-        })
+            next_task_id: snapshot.next_task_id,
+            tasks: snapshot.tasks,
+            done: snapshot.done,
+            leases: snapshot
+                .leases
+                .into_iter()
+                .map(|lease| (lease.task.id, lease))
+                .collect(),
+            next_lease_token: snapshot.next_lease_token,
+            outbox: snapshot
+                .outbox
+                .into_iter()
+                .map(|handoff| (handoff.id, handoff))
+                .collect(),
+            next_handoff_id: snapshot.next_handoff_id,
+            handoff_retry_scheduled: false,
+            received: snapshot.received,
+            history: VecDeque::new(),
+            redo: Vec::new(),
+            task_waiters: Vec::new(),
+            offered_tasks: HashSet::new(),
+            storage,
+            log,
+            pending_ops: 0,
+        };
+        for record in records {
+            pile.apply(record.op);
+        }
+        pile.trim_archive();
+        // start every run from a fresh snapshot, this also gets rid of a torn
+        // record at the end of the log
+        pile.compact()
+            .map_err(|err| eprintln!("Failed to compact pile {}: {err}", pile.info.id))?;
+        // timers do not survive a restart
+        for lease in pile.leases.values() {
+            pile.schedule_lease_expiry(lease);
+        }
+        pile.schedule_handoff_retry();
+        Ok(pile)
     }
 
     #[terminate]
@@ -60,90 +1159,1334 @@ impl Pile {
     }
 
     #[handle_request]
-    fn complete_current(&mut self) -> Option<Task> {
-        if self.info.is_stack {
-            return self.tasks.pop_back();
+    fn complete_current(&mut self) -> Result<Option<Task>, ApiError> {
+        let Some(task_id) = self.top().map(|task| task.id) else {
+            return Ok(None);
+        };
+        self.complete(task_id).map(Some)
+    }
+
+    #[handle_request]
+    fn list_done(&self, offset: usize, limit: usize) -> Page<Task> {
+        // most recently completed first
+        let items = self
+            .done
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        Page {
+            total: self.done.len(),
+            offset,
+            limit,
+            items,
         }
-        self.tasks.pop_front()
     }
 
     #[handle_request]
-    fn push_task(&mut self, new_task: Task) -> () {
-        self.tasks.push_back(new_task)
+    fn reopen_task(&mut self, task_id: u32) -> Result<Task, ApiError> {
+        if !self.done.iter().any(|task| task.id == task_id) {
+            return Err(ApiError::TaskNotFound(self.info.id, task_id));
+        }
+        self.record(PileOp::Reopen {
+            task_id,
+            at: Utc::now(),
+        })?;
+        let task = self.tasks.back().cloned().unwrap();
+        self.notify(PileEventKind::TaskReopened, Some(&task));
+        Ok(task)
     }
 
     #[handle_request]
-    fn pile_top<'a>(&self) -> Option<Task> {
-        // we want to ALWAYS give the top element in the stack
-        let top = if self.info.is_stack {
-            self.tasks.back()
-        } else {
-            self.tasks.front()
-        };
-        top.map(|t| t.clone())
+    fn push_task(&mut self, new_task: CreateTaskDTO) -> Result<Task, ApiError> {
+        let task = self.new_task(new_task);
+        self.record(PileOp::Push(task.clone()))?;
+        self.notify(PileEventKind::TaskPushed, Some(&task));
+        Ok(task)
     }
-}
 
-// a place to register all the piles
+    #[handle_request]
+    fn insert_task(&mut self, depth: usize, new_task: CreateTaskDTO) -> Result<Task, ApiError> {
+        self.require_positional()?;
+        let task = self.new_task(new_task);
+        let index = self.info.policy.depth_to_index(depth, self.tasks.len() + 1);
+        self.record(PileOp::Insert {
+            task: task.clone(),
+            index,
+        })?;
+        self.notify(PileEventKind::TaskPushed, Some(&task));
+        Ok(task)
+    }
 
-#[derive(Debug, Default)]
-struct PileRegistry {
-    counter: u32,
-    piles: HashMap<u32, ProcessRef<Pile>>,
-}
+    #[handle_request]
+    fn move_task(&mut self, task_id: u32, depth: usize) -> Result<Vec<Task>, ApiError> {
+        self.move_to_depth(task_id, depth)
+    }
 
-#[abstract_process]
-impl PileRegistry {
-    #[init]
-    fn init(_: Config<Self>, _: ()) -> Result<Self, ()> {
-        Ok(Self::default())
+    /// Sends the top task to the bottom of the pile.
+    #[handle_request]
+    fn bury_top(&mut self) -> Result<Vec<Task>, ApiError> {
+        let Some(top) = self.top().map(|task| task.id) else {
+            return Ok(Vec::new());
+        };
+        self.move_to_depth(top, self.tasks.len() - 1)
     }
 
-    #[terminate]
-    fn terminate(self) {
-        println!("Shutdown process");
+    #[handle_request]
+    fn swap_top(&mut self) -> Result<Vec<Task>, ApiError> {
+        if self.tasks.len() < 2 {
+            return Err(ApiError::Conflict(
+                "swapping needs at least two tasks".to_string(),
+            ));
+        }
+        let top = self.top().map(|task| task.id).unwrap();
+        self.move_to_depth(top, 1)
     }
 
-    #[handle_link_death]
-    fn handle_link_death(&self, _tag: Tag) {
-        println!("Link trapped");
+    #[handle_request]
+    fn update_task(&mut self, task_id: u32, update: UpdateTaskDTO) -> Result<Task, ApiError> {
+        let mut task = self
+            .tasks
+            .iter()
+            .find(|task| task.id == task_id)
+            .cloned()
+            .ok_or(ApiError::TaskNotFound(self.info.id, task_id))?;
+        if let Some(title) = update.title {
+            task.title = title;
+        }
+        if let Some(description) = update.description {
+            task.description = description;
+        }
+        if let Some(status) = update.status {
+            task.status = status;
+        }
+        if let Some(priority) = update.priority {
+            task.priority = priority;
+        }
+        if let Some(due_at) = update.due_at {
+            task.due_at = due_at;
+        }
+        if let Some(tags) = update.tags {
+            task.tags = normalize_tags(tags);
+        }
+        task.updated_at = Some(Utc::now());
+        self.record(PileOp::Update(task.clone()))?;
+        self.notify(PileEventKind::TaskUpdated, Some(&task));
+        Ok(task)
     }
 
     #[handle_request]
-    fn create_pile(
-        &mut self,
-        name: String,
-        description: String,
-        is_stack: bool,
-    ) -> (PileInfo, ProcessRef<Pile>) {
-        let id = self.counter;
-        self.counter += 1;
-        let info = PileInfo {
-            id,
-            name,
-            description,
-            is_stack,
+    fn pile_top<'a>(&self) -> Option<Task> {
+        // we want to ALWAYS give the top element in the stack
+        self.top().cloned()
+    }
+
+    /// Hands the top task to `consumer` for `visibility`. Until it is acked the
+    /// task is not part of the pile, if it is not acked in time it goes back.
+    #[handle_request]
+    fn claim(&mut self, consumer: String, visibility: Duration) -> Result<Option<Lease>, ApiError> {
+        let Some(top) = self.top().cloned() else {
+            return Ok(None);
+        };
+        let lease = Lease {
+            token: self.next_lease_token,
+            consumer,
+            expires_at: Utc::now() + chrono::Duration::from_std(visibility).unwrap_or_default(),
+            index: 0,
+            task: top,
         };
-        let process_ref = Pile::start(info.clone()).unwrap();
-        self.piles.insert(id, process_ref);
-        (info, process_ref)
+        let task_id = lease.task.id;
+        self.commit(PileOp::Claim(lease))?;
+        let lease = self.leases[&task_id].clone();
+        self.schedule_lease_expiry(&lease);
+        self.notify(PileEventKind::TaskClaimed, Some(&lease.task));
+        Ok(Some(lease))
     }
 
     #[handle_request]
-    fn get_pile(&mut self, pile_id: u32) -> Option<ProcessRef<Pile>> {
-        self.piles.get(&pile_id).map(|pile| pile.clone())
+    fn ack(&mut self, task_id: u32, token: u64) -> Result<Task, ApiError> {
+        self.active_lease(task_id, token)?;
+        self.complete(task_id)
     }
 
     #[handle_request]
-    fn delete_pile(&mut self, pile_id: u32) -> () {
-        if let Some(pile) = self.piles.get(&pile_id) {
-            pile.kill();
-            self.piles.remove(&pile_id);
+    fn nack(&mut self, task_id: u32, token: u64, reason: Option<String>) -> Result<(), ApiError> {
+        self.active_lease(task_id, token)?;
+        self.fail(task_id, reason.unwrap_or_else(|| "nacked".to_string()))
+    }
+
+    #[handle_request]
+    fn extend(
+        &mut self,
+        task_id: u32,
+        token: u64,
+        visibility: Duration,
+    ) -> Result<Lease, ApiError> {
+        self.active_lease(task_id, token)?;
+        let expires_at = Utc::now() + chrono::Duration::from_std(visibility).unwrap_or_default();
+        self.commit(PileOp::Extend {
+            task_id,
+            expires_at,
+        })?;
+        // the timer of the old deadline finds the lease extended and leaves it be
+        let lease = self.leases[&task_id].clone();
+        self.schedule_lease_expiry(&lease);
+        Ok(lease)
+    }
+
+    #[handle_request]
+    fn list_leases(&self) -> Vec<Lease> {
+        let mut leases: Vec<Lease> = self.leases.values().cloned().collect();
+        leases.sort_by_key(|lease| lease.expires_at);
+        leases
+    }
+
+    #[handle_message]
+    fn expire_lease(&mut self, task_id: u32, token: u64) {
+        let expired = self
+            .leases
+            .get(&task_id)
+            .is_some_and(|lease| lease.token == token && lease.expires_at <= Utc::now());
+        if expired {
+            if let Err(err) = self.fail(task_id, "lease expired".to_string()) {
+                // try again a bit later, the task must not stay hidden forever
+                eprintln!("Failed to release expired lease of task {task_id}: {err:?}");
+                self.this
+                    .with_delay(Duration::from_secs(1))
+                    .expire_lease(task_id, token);
+            }
         }
     }
-}
 
-// =====================================
+    #[handle_request]
+    fn configure(&mut self, info: PileInfo) -> Result<(), ApiError> {
+        self.commit(PileOp::Configure(info))?;
+        Ok(())
+    }
+
+    /// The open tasks in insertion order and the archive, oldest first.
+    /// Leased tasks count as open, they are back where they were claimed from.
+    #[handle_request]
+    fn export_tasks(&self) -> (Vec<Task>, Vec<Task>) {
+        let mut tasks = self.tasks.clone();
+        let mut leases: Vec<&Lease> = self.leases.values().collect();
+        leases.sort_by_key(|lease| lease.index);
+        for lease in leases {
+            tasks.insert(lease.index.min(tasks.len()), lease.task.clone());
+        }
+        (tasks.into(), self.done.clone())
+    }
+
+    /// Adds exported tasks with their ids, which is only possible while the
+    /// pile has none of its own.
+    #[handle_request]
+    fn load_tasks(&mut self, tasks: Vec<Task>, done: Vec<Task>) -> Result<(), ApiError> {
+        if self.next_task_id > 0 {
+            return Err(ApiError::Conflict(format!(
+                "pile {} already has tasks",
+                self.info.id
+            )));
+        }
+        self.commit(PileOp::Load { tasks, done })?;
+        self.notify(PileEventKind::Imported, None);
+        Ok(())
+    }
+
+    /// Adds tasks under new ids, finished ones straight to the archive. The
+    /// open ones go in in the order given, or, with `top_first`, so that the
+    /// first one is handed out first.
+    #[handle_request]
+    fn import_tasks(&mut self, tasks: Vec<Task>, top_first: bool) -> Result<Vec<Task>, ApiError> {
+        let (mut open, mut done): (Vec<Task>, Vec<Task>) = tasks
+            .into_iter()
+            .partition(|task| matches!(task.status, TaskStatus::Open | TaskStatus::InProgress));
+        if top_first && self.info.policy == OrderingPolicy::Lifo {
+            open.reverse();
+        }
+        for (id, task) in (self.next_task_id..).zip(open.iter_mut().chain(&mut done)) {
+            task.id = id;
+            // attempts count against the pile they were made in
+            task.attempts = 0;
+            task.failures.clear();
+        }
+        self.commit(PileOp::Load {
+            tasks: open.clone(),
+            done: done.clone(),
+        })?;
+        self.notify(PileEventKind::Imported, None);
+        open.extend(done);
+        Ok(open)
+    }
+
+    #[handle_message]
+    fn receive_handoff(&mut self, source: u32, handoff: Handoff, confirmed_below: u64) {
+        let handoff_id = handoff.id;
+        if let Err(err) = self.take_in(source, handoff, confirmed_below) {
+            // no confirmation, so the sender tries again
+            eprintln!("Failed to receive handoff {handoff_id} from pile {source}: {err:?}");
+            return;
+        }
+        let sender_name = pile_process_name(self.storage.workspace(), source);
+        if let Some(sender) = ProcessRef::<Pile>::lookup(&sender_name) {
+            sender.handoff_delivered(handoff_id);
+        }
+    }
+
+    /// Like `receive_handoff`, for a caller that confirms the handoff itself.
+    #[handle_request]
+    fn accept_handoff(
+        &mut self,
+        source: u32,
+        handoff: Handoff,
+        confirmed_below: u64,
+    ) -> Result<Option<Task>, ApiError> {
+        self.take_in(source, handoff, confirmed_below)
+    }
+
+    /// First half of moving a task to another pile: the task leaves this pile
+    /// for the outbox. Returns the handoff and the `confirmed_below` mark to
+    /// deliver it with.
+    #[handle_request]
+    fn move_to_pile(
+        &mut self,
+        task_id: Option<u32>,
+        target: u32,
+    ) -> Result<(Handoff, u64), ApiError> {
+        let task = match task_id {
+            Some(task_id) => self.tasks.iter().find(|task| task.id == task_id),
+            None => self.top(),
+        };
+        let Some(task) = task.cloned() else {
+            return Err(match task_id {
+                Some(task_id) => ApiError::TaskNotFound(self.info.id, task_id),
+                None => ApiError::Conflict("the pile is empty".to_string()),
+            });
+        };
+        let handoff = self.take_out(task, target)?;
+        self.notify(PileEventKind::TaskMoved, Some(&handoff.task));
+        Ok((handoff, self.confirmed_below()))
+    }
+
+    #[handle_message]
+    fn handoff_delivered(&mut self, handoff_id: u64) {
+        if !self.outbox.contains_key(&handoff_id) {
+            return;
+        }
+        if let Err(err) = self.commit(PileOp::Delivered { handoff_id }) {
+            // harmless, the handoff is sent again and confirmed again
+            eprintln!("Failed to record delivery of handoff {handoff_id}: {err:?}");
+        }
+    }
+
+    #[handle_message]
+    fn retry_handoffs(&mut self) {
+        self.handoff_retry_scheduled = false;
+        for handoff in self.outbox.values() {
+            self.deliver(handoff);
+        }
+        // a deleted pile never confirms, the registry answers with
+        // `return_handoffs` if any of the targets is gone
+        let targets: BTreeSet<u32> = self.outbox.values().map(|h| h.target).collect();
+        let registry_name = registry_process_name(self.storage.workspace());
+        if let Some(registry) = ProcessRef::<PileRegistry>::lookup(&registry_name) {
+            if !targets.is_empty() {
+                registry.check_handoff_targets(self.info.id, targets.into_iter().collect());
+            }
+        }
+        self.schedule_handoff_retry();
+    }
+
+    /// Takes back the tasks handed off to the `targets`, which no longer
+    /// exist.
+    #[handle_message]
+    fn return_handoffs(&mut self, targets: Vec<u32>) {
+        let returned: Vec<u64> = self
+            .outbox
+            .values()
+            .filter(|handoff| targets.contains(&handoff.target))
+            .map(|handoff| handoff.id)
+            .collect();
+        for handoff_id in returned {
+            if let Err(err) = self.commit(PileOp::Return { handoff_id }) {
+                // still in the outbox, the next retry asks again
+                eprintln!("Failed to take back handoff {handoff_id}: {err:?}");
+                return;
+            }
+            self.notify(PileEventKind::TaskPushed, self.tasks.back());
+        }
+    }
+
+    /// Like `pile_top`, but an empty pile parks the caller until a task
+    /// arrives or `wait` runs out.
+    #[handle_deferred_request]
+    fn wait_for_task(&mut self, wait: Duration, response: DeferredResponse<Option<Task>, Self>) {
+        if let Some(top) = self.top() {
+            response.send_response(Some(top.clone()));
+            return;
+        }
+        // none of the tasks offered before is left
+        self.offered_tasks.clear();
+        self.task_waiters.push(TaskWaiter {
+            deadline: Instant::now() + wait,
+            response,
+        });
+        self.this.with_delay(wait).expire_task_waiters();
+    }
+
+    #[handle_message]
+    fn expire_task_waiters(&mut self) {
+        let now = Instant::now();
+        let (expired, waiting) = std::mem::take(&mut self.task_waiters)
+            .into_iter()
+            .partition(|waiter| waiter.deadline <= now);
+        self.task_waiters = waiting;
+        for waiter in expired {
+            waiter.response.send_response(None);
+        }
+    }
+
+    #[handle_request]
+    fn list_tasks(&self) -> Vec<Task> {
+        self.ordered_tasks()
+    }
+
+    #[handle_request]
+    fn undo(&mut self) -> Result<Vec<Task>, ApiError> {
+        let op = self
+            .history
+            .pop_back()
+            .ok_or_else(|| ApiError::Conflict("nothing to undo".to_string()))?;
+        match self.commit(op.clone()) {
+            Ok(Some(inverse)) => self.redo.push(inverse),
+            // the task was changed in a way the history does not know about
+            Ok(None) => {
+                return Err(ApiError::Conflict(
+                    "the last operation can no longer be undone".to_string(),
+                ))
+            }
+            Err(err) => {
+                self.history.push_back(op);
+                return Err(err);
+            }
+        }
+        self.notify(PileEventKind::Reverted, None);
+        Ok(self.ordered_tasks())
+    }
+
+    #[handle_request]
+    fn redo(&mut self) -> Result<Vec<Task>, ApiError> {
+        let op = self
+            .redo
+            .pop()
+            .ok_or_else(|| ApiError::Conflict("nothing to redo".to_string()))?;
+        match self.commit(op.clone()) {
+            Ok(Some(inverse)) => self.remember(inverse),
+            Ok(None) => {
+                return Err(ApiError::Conflict(
+                    "the last undo can no longer be redone".to_string(),
+                ))
+            }
+            Err(err) => {
+                self.redo.push(op);
+                return Err(err);
+            }
+        }
+        self.notify(PileEventKind::Reverted, None);
+        Ok(self.ordered_tasks())
+    }
+}
+
+// =====================================
+// Pile supervisor
+// =====================================
+
+// every pile gets its own supervisor, so a crash only restarts that one pile.
+// The restarted pile reloads its snapshot and log in `init` and registers under
+// the same name again.
+pub struct PileSupervisor;
+
+impl Supervisor for PileSupervisor {
+    type Arg = (PileInfo, Storage);
+    type Children = (Pile,);
+
+    fn init(config: &mut SupervisorConfig<Self>, (info, storage): Self::Arg) {
+        let name = pile_process_name(storage.workspace(), info.id);
+        config.set_strategy(SupervisorStrategy::OneForOne);
+        config.children_args((((info, storage), Some(name)),));
+    }
+}
+
+// pile ids are only unique within a workspace
+fn pile_process_name(workspace: &str, pile_id: u32) -> String {
+    format!("pile-{workspace}-{pile_id}")
+}
+
+// =====================================
+// Registry process
+// =====================================
+
+// a place to register all the piles of a workspace
+
+struct PileEntry {
+    info: PileInfo,
+    supervisor: ProcessRef<PileSupervisor>,
+    // identifies the link to the supervisor in `handle_link_death`
+    tag: Tag,
+}
+
+// a feed or event stream client waiting for activity
+struct EventWaiter {
+    caller: Caller,
+    after: u64,
+    deadline: Instant,
+    response: DeferredResponse<(u64, Vec<ActivityEvent>), PileRegistry>,
+}
+
+// how many events are kept around for clients resuming with `Last-Event-ID`
+const ACTIVITY_BUFFER: usize = 1024;
+
+struct PileRegistry {
+    this: ProcessRef<PileRegistry>,
+    counter: u32,
+    piles: HashMap<u32, PileEntry>,
+    storage: Storage,
+    log: WriteAheadLog<RegistryOp>,
+    pending_ops: u64,
+    // recent activity of all piles, only kept in memory
+    activity: VecDeque<ActivityEvent>,
+    next_event_id: u64,
+    event_waiters: Vec<EventWaiter>,
+}
+
+impl PileRegistry {
+    /// The info of a pile and the role `caller` has on it. Piles the caller
+    /// has no role on look like they do not exist.
+    fn access(&self, pile_id: u32, caller: &Caller) -> Result<(&PileInfo, Role), ApiError> {
+        self.piles
+            .get(&pile_id)
+            .and_then(|entry| Some((&entry.info, entry.info.role_of(caller)?)))
+            .ok_or(ApiError::PileNotFound(pile_id))
+    }
+
+    /// Like `access`, but fails unless the caller has at least `required`.
+    fn require(
+        &self,
+        pile_id: u32,
+        caller: &Caller,
+        required: Role,
+    ) -> Result<&PileInfo, ApiError> {
+        let (info, role) = self.access(pile_id, caller)?;
+        require_role(role, required)?;
+        Ok(info)
+    }
+
+    /// Logs a changed pile info and hands it to the running pile.
+    fn store_info(&mut self, mut info: PileInfo) -> Result<PileInfo, ApiError> {
+        let pile_id = info.id;
+        info.revision += 1;
+        self.record(&RegistryOp::Updated(info.clone()))?;
+        if let Some(entry) = self.piles.get_mut(&pile_id) {
+            entry.info = info.clone();
+        }
+        self.maybe_compact();
+        // our copy is committed either way, a pile that misses the update
+        // picks it up the next time it is started
+        match self.live_pile(pile_id) {
+            Ok(pile) => {
+                if let Err(err) = pile.configure(info.clone()) {
+                    eprintln!("Failed to reconfigure pile {pile_id}: {err:?}");
+                }
+            }
+            Err(err) => eprintln!("Failed to reconfigure pile {pile_id}: {err:?}"),
+        }
+        Ok(info)
+    }
+
+    /// Checks a pile that `pile_id` sends tasks to, `role` names it in errors.
+    fn check_target(
+        &self,
+        pile_id: u32,
+        target: Option<u32>,
+        role: &str,
+        caller: &Caller,
+    ) -> Result<(), ApiError> {
+        match target {
+            Some(target) if target == pile_id => Err(ApiError::BadRequest(format!(
+                "a pile cannot be its own {role}"
+            ))),
+            // tasks arrive there as if the caller pushed them
+            Some(target) if self.require(target, caller, Role::Contributor).is_err() => Err(
+                ApiError::BadRequest(format!("{role} {target} does not exist")),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn spawn_pile(info: &PileInfo, storage: &Storage) -> Result<PileEntry, ApiError> {
+        let tag = Tag::new();
+        let supervisor = PileSupervisor::link_with(tag)
+            .start((info.clone(), storage.clone()))
+            .map_err(|err| ApiError::SpawnFailed(format!("{err:?}")))?;
+        Ok(PileEntry {
+            info: info.clone(),
+            supervisor,
+            tag,
+        })
+    }
+
+    /// Returns a reference to the running pile, starting it again from its
+    /// persisted state if the supervisor could not keep it alive.
+    fn live_pile(&mut self, pile_id: u32) -> Result<ProcessRef<Pile>, ApiError> {
+        if !self.piles.contains_key(&pile_id) {
+            return Err(ApiError::PileNotFound(pile_id));
+        }
+        let name = pile_process_name(self.storage.workspace(), pile_id);
+        if let Some(pile) = ProcessRef::<Pile>::lookup(&name) {
+            return Ok(pile);
+        }
+        self.respawn(pile_id)?;
+        ProcessRef::<Pile>::lookup(&name)
+            .ok_or_else(|| ApiError::SpawnFailed(format!("pile {pile_id} did not register")))
+    }
+
+    fn respawn(&mut self, pile_id: u32) -> Result<(), ApiError> {
+        let Some(entry) = self.piles.remove(&pile_id) else {
+            return Ok(());
+        };
+        println!("Restarting pile {pile_id}");
+        // forget the old entry first, its link death must not trigger another respawn
+        entry.supervisor.kill();
+        match Self::spawn_pile(&entry.info, &self.storage) {
+            Ok(fresh) => {
+                self.piles.insert(pile_id, fresh);
+                Ok(())
+            }
+            Err(err) => {
+                // keep the pile registered, the next request tries again
+                self.piles.insert(pile_id, entry);
+                Err(err)
+            }
+        }
+    }
+
+    fn push_activity(&mut self, event: PileEvent, owner: Option<u32>) {
+        let id = self.next_event_id;
+        self.next_event_id += 1;
+        self.activity.push_back(ActivityEvent { id, event, owner });
+        while self.activity.len() > ACTIVITY_BUFFER {
+            self.activity.pop_front();
+        }
+
+        for waiter in std::mem::take(&mut self.event_waiters) {
+            let events = self.activity_after(waiter.after, &waiter.caller);
+            // nothing this waiter may see, keep it waiting
+            if events.is_empty() {
+                self.event_waiters.push(waiter);
+            } else {
+                waiter.response.send_response((id, events));
+            }
+        }
+    }
+
+    fn activity_after(&self, after: u64, caller: &Caller) -> Vec<ActivityEvent> {
+        self.activity
+            .iter()
+            .filter(|event| event.id > after && self.may_see(event, caller))
+            .cloned()
+            .collect()
+    }
+
+    // id of the latest event, which clients resume from next time
+    fn last_event_id(&self) -> u64 {
+        self.next_event_id - 1
+    }
+
+    fn may_see(&self, event: &ActivityEvent, caller: &Caller) -> bool {
+        match self.piles.get(&event.event.pile_id) {
+            Some(entry) => entry.info.role_of(caller).is_some(),
+            // the pile is gone, only its owner hears about that
+            None => caller.owns(event.owner),
+        }
+    }
+
+    fn announce(&mut self, info: &PileInfo, kind: PileEventKind) {
+        let event = PileEvent {
+            pile_id: info.id,
+            kind,
+            task: None,
+            at: Utc::now(),
+        };
+        self.push_activity(event, info.owner);
+    }
+
+    /// Appends `op` to the registry log. The caller applies it afterwards.
+    fn record(&mut self, op: &RegistryOp) -> Result<(), ApiError> {
+        self.log
+            .append(op)
+            .map_err(|err| ApiError::Storage(err.to_string()))?;
+        self.pending_ops += 1;
+        Ok(())
+    }
+
+    // called once the recorded operation was applied
+    fn maybe_compact(&mut self) {
+        if self.pending_ops >= COMPACT_AFTER {
+            if let Err(err) = self.compact() {
+                eprintln!("Failed to compact registry: {err}");
+            }
+        }
+    }
+
+    fn compact(&mut self) -> std::io::Result<()> {
+        let mut piles: Vec<PileInfo> = self.piles.values().map(|e| e.info.clone()).collect();
+        piles.sort_by_key(|info| info.id);
+        let snapshot = RegistrySnapshot {
+            seq: self.log.last_seq(),
+            counter: self.counter,
+            piles,
+        };
+        self.storage.save_registry(&snapshot)?;
+        self.log.truncate()?;
+        self.pending_ops = 0;
+        Ok(())
+    }
+}
+
+#[abstract_process]
+impl PileRegistry {
+    #[init]
+    fn init(config: Config<Self>, storage: Storage) -> Result<Self, ()> {
+        // supervisors that give up are restarted by us, they must not take the registry down
+        config.die_if_link_dies(false);
+
+        let snapshot = storage
+            .load_registry()
+            .map_err(|err| eprintln!("Failed to load registry of {}: {err}", storage.workspace()))?
+            .unwrap_or_default();
+        let mut log = storage.registry_log();
+        let records = log.replay(snapshot.seq).map_err(|err| {
+            eprintln!(
+                "Failed to replay registry log of {}: {err}",
+                storage.workspace()
+            )
+        })?;
+
+        let mut counter = snapshot.counter;
+        let mut infos: BTreeMap<u32, PileInfo> = snapshot
+            .piles
+            .into_iter()
+            .map(|info| (info.id, info))
+            .collect();
+        for record in records {
+            match record.op {
+                RegistryOp::Created(info) | RegistryOp::Updated(info) => {
+                    counter = counter.max(info.id + 1);
+                    infos.insert(info.id, info);
+                }
+                RegistryOp::Deleted { pile_id } => {
+                    infos.remove(&pile_id);
+                    // the process may have died before cleaning up after itself
+                    if let Err(err) = storage.remove_pile(pile_id) {
+                        eprintln!("Failed to remove data of pile {pile_id}: {err}");
+                    }
+                }
+            }
+        }
+
+        // refuse to start rather than come up without some of the piles, the
+        // next compaction would otherwise drop them from disk for good
+        let mut piles = HashMap::new();
+        for (id, info) in infos {
+            let entry = Self::spawn_pile(&info, &storage)
+                .map_err(|err| eprintln!("Failed to restore pile {id}: {err:?}"))?;
+            piles.insert(id, entry);
+        }
+
+        let mut registry = Self {
+            this: config.self_ref(),
+            counter,
+            piles,
+            storage,
+            log,
+            pending_ops: 0,
+            activity: VecDeque::new(),
+            // 0 is never handed out, so it can be used to ask for everything
+            next_event_id: 1,
+            event_waiters: Vec::new(),
+        };
+        registry
+            .compact()
+            .map_err(|err| eprintln!("Failed to compact registry: {err}"))?;
+        Ok(registry)
+    }
+
+    #[terminate]
+    fn terminate(self) {
+        println!("Shutdown process");
+    }
+
+    #[handle_link_death]
+    fn handle_link_death(&mut self, tag: Tag) {
+        let dead = self
+            .piles
+            .iter()
+            .find(|(_, entry)| entry.tag == tag)
+            .map(|(id, _)| *id);
+        // links of deleted or already replaced piles are not found here
+        if let Some(pile_id) = dead {
+            if let Err(err) = self.respawn(pile_id) {
+                eprintln!("Failed to restart pile {pile_id}: {err:?}");
+            }
+        }
+    }
+
+    /// Creates a pile under the next free id, or under `id` if one is given.
+    #[handle_request]
+    fn create_pile(
+        &mut self,
+        dto: CreatePileDTO,
+        caller: Caller,
+        id: Option<u32>,
+    ) -> Result<(PileInfo, ProcessRef<Pile>), ApiError> {
+        let id = match id {
+            Some(id) if self.piles.contains_key(&id) => {
+                return Err(ApiError::Conflict(format!("pile {id} exists already")))
+            }
+            Some(id) => id,
+            None => self.counter,
+        };
+        self.check_target(id, dto.dead_letter, "dead-letter pile", &caller)?;
+        let next = dto.on_complete.as_ref().map(|next| next.pile_id);
+        self.check_target(id, next, "on_complete pile", &caller)?;
+        let info = PileInfo {
+            id,
+            name: dto.name,
+            description: dto.description,
+            policy: dto.policy,
+            history_depth: dto.history_depth.unwrap_or_else(default_history_depth),
+            max_attempts: dto.max_attempts,
+            dead_letter: dto.dead_letter,
+            on_complete: dto.on_complete,
+            owner: caller.key_id,
+            acl: BTreeMap::new(),
+            revision: 0,
+        };
+        let entry = Self::spawn_pile(&info, &self.storage)?;
+        if let Err(err) = self.record(&RegistryOp::Created(info.clone())) {
+            entry.supervisor.kill();
+            return Err(err);
+        }
+        // only burn the id once the pile actually exists
+        self.counter = self.counter.max(id + 1);
+        self.piles.insert(id, entry);
+        self.maybe_compact();
+        self.announce(&info, PileEventKind::PileCreated);
+        let process_ref = self.live_pile(id)?;
+        Ok((info, process_ref))
+    }
+
+    /// Which of `ids` are in use, whoever owns the piles.
+    #[handle_request]
+    fn taken_ids(&self, ids: Vec<u32>) -> Vec<u32> {
+        ids.into_iter()
+            .filter(|id| self.piles.contains_key(id))
+            .collect()
+    }
+
+    /// The running pile and the caller's role on it. Handlers check the
+    /// role against what they are about to do.
+    #[handle_request]
+    fn get_pile(
+        &mut self,
+        pile_id: u32,
+        caller: Caller,
+    ) -> Result<(ProcessRef<Pile>, Role), ApiError> {
+        let (_, role) = self.access(pile_id, &caller)?;
+        Ok((self.live_pile(pile_id)?, role))
+    }
+
+    #[handle_request]
+    fn get_pile_info(&self, pile_id: u32, caller: Caller) -> Result<PileInfo, ApiError> {
+        self.access(pile_id, &caller)
+            .map(|(info, role)| info.clone().shown_to(role))
+    }
+
+    #[handle_request]
+    fn list_piles(&self, caller: Caller) -> Vec<PileInfo> {
+        let mut infos: Vec<PileInfo> = self
+            .piles
+            .values()
+            .filter_map(|e| Some(e.info.clone().shown_to(e.info.role_of(&caller)?)))
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    #[handle_request]
+    fn update_pile(
+        &mut self,
+        pile_id: u32,
+        update: UpdatePileDTO,
+        caller: Caller,
+    ) -> Result<PileInfo, ApiError> {
+        let mut info = self.require(pile_id, &caller, Role::Admin)?.clone();
+        if let Some(name) = update.name {
+            info.name = name;
+        }
+        if let Some(description) = update.description {
+            info.description = description;
+        }
+        if let Some(max_attempts) = update.max_attempts {
+            info.max_attempts = max_attempts;
+        }
+        if let Some(dead_letter) = update.dead_letter {
+            self.check_target(pile_id, dead_letter, "dead-letter pile", &caller)?;
+            info.dead_letter = dead_letter;
+        }
+        if let Some(on_complete) = update.on_complete {
+            let next = on_complete.as_ref().map(|next| next.pile_id);
+            self.check_target(pile_id, next, "on_complete pile", &caller)?;
+            info.on_complete = on_complete;
+        }
+        self.store_info(info)
+    }
+
+    #[handle_request]
+    fn grant_role(
+        &mut self,
+        pile_id: u32,
+        key_id: u32,
+        role: Role,
+        caller: Caller,
+    ) -> Result<PileInfo, ApiError> {
+        let mut info = self.require(pile_id, &caller, Role::Admin)?.clone();
+        if info.owner == Some(key_id) {
+            return Err(ApiError::Conflict(
+                "the owner of a pile always has every role".to_string(),
+            ));
+        }
+        info.acl.insert(key_id, role);
+        self.store_info(info)
+    }
+
+    #[handle_request]
+    fn revoke_role(&mut self, pile_id: u32, key_id: u32, caller: Caller) -> Result<(), ApiError> {
+        let mut info = self.require(pile_id, &caller, Role::Admin)?.clone();
+        if info.acl.remove(&key_id).is_some() {
+            self.store_info(info)?;
+        }
+        Ok(())
+    }
+
+    /// Drops every role a revoked key was given.
+    #[handle_request]
+    fn forget_key(&mut self, key_id: u32) -> Result<(), ApiError> {
+        let infos: Vec<PileInfo> = self
+            .piles
+            .values()
+            .filter(|entry| entry.info.acl.contains_key(&key_id))
+            .map(|entry| entry.info.clone())
+            .collect();
+        for mut info in infos {
+            info.acl.remove(&key_id);
+            self.store_info(info)?;
+        }
+        Ok(())
+    }
+
+    #[handle_request]
+    fn delete_pile(&mut self, pile_id: u32, caller: Caller) -> Result<(), ApiError> {
+        self.require(pile_id, &caller, Role::Admin)?;
+        for entry in self.piles.values() {
+            let role = if entry.info.dead_letter == Some(pile_id) {
+                "dead-letter pile"
+            } else if entry
+                .info
+                .on_complete
+                .as_ref()
+                .is_some_and(|next| next.pile_id == pile_id)
+            {
+                "on_complete pile"
+            } else {
+                continue;
+            };
+            return Err(ApiError::Conflict(format!(
+                "pile {pile_id} is the {role} of pile {}",
+                entry.info.id
+            )));
+        }
+        self.record(&RegistryOp::Deleted { pile_id })?;
+        let Some(entry) = self.piles.remove(&pile_id) else {
+            return Ok(());
+        };
+        // killed rather than shut down, a pile busy with a long request must
+        // not hold up the registry. The supervisor goes first, or it would
+        // restart the pile.
+        entry.supervisor.kill();
+        let pile_name = pile_process_name(self.storage.workspace(), pile_id);
+        if let Some(pile) = ProcessRef::<Pile>::lookup(&pile_name) {
+            pile.kill();
+        }
+        if let Err(err) = self.storage.remove_pile(pile_id) {
+            // the registry no longer knows the pile, a stale file is harmless
+            eprintln!("Failed to remove data of pile {pile_id}: {err}");
+        }
+        self.maybe_compact();
+        self.announce(&entry.info, PileEventKind::PileDeleted);
+        Ok(())
+    }
+
+    /// Tells the `source` pile which of the `targets` of its pending
+    /// handoffs were deleted.
+    #[handle_message]
+    fn check_handoff_targets(&mut self, source: u32, targets: Vec<u32>) {
+        let gone: Vec<u32> = targets
+            .into_iter()
+            .filter(|target| !self.piles.contains_key(target))
+            .collect();
+        if gone.is_empty() {
+            return;
+        }
+        let source_name = pile_process_name(self.storage.workspace(), source);
+        if let Some(pile) = ProcessRef::<Pile>::lookup(&source_name) {
+            pile.return_handoffs(gone);
+        }
+    }
+
+    #[handle_message]
+    fn publish(&mut self, event: PileEvent) {
+        let owner = self
+            .piles
+            .get(&event.pile_id)
+            .and_then(|entry| entry.info.owner);
+        self.push_activity(event, owner);
+    }
+
+    /// Answers with the buffered events newer than `after`, or parks until
+    /// the next event arrives or `wait` runs out. Along with the events comes
+    /// the id to resume from, even if there were none.
+    #[handle_deferred_request]
+    fn activity_since(
+        &mut self,
+        after: Option<u64>,
+        wait: Duration,
+        caller: Caller,
+        response: DeferredResponse<(u64, Vec<ActivityEvent>), Self>,
+    ) {
+        // new clients start with what happens from now on, so do clients
+        // holding an id from before the registry restarted, ids start over
+        let after = match after {
+            Some(after) if after < self.next_event_id => after,
+            _ => self.last_event_id(),
+        };
+        let events = self.activity_after(after, &caller);
+        if !events.is_empty() {
+            response.send_response((self.last_event_id(), events));
+            return;
+        }
+        self.event_waiters.push(EventWaiter {
+            caller,
+            after,
+            deadline: Instant::now() + wait,
+            response,
+        });
+        self.this.with_delay(wait).expire_event_waiters();
+    }
+
+    #[handle_message]
+    fn expire_event_waiters(&mut self) {
+        let now = Instant::now();
+        let (expired, waiting) = std::mem::take(&mut self.event_waiters)
+            .into_iter()
+            .partition(|waiter| waiter.deadline <= now);
+        self.event_waiters = waiting;
+        for waiter in expired {
+            waiter
+                .response
+                .send_response((self.last_event_id(), Vec::new()));
+        }
+    }
+}
+
+// =====================================
+// Workspaces
+// =====================================
+
+// Every workspace has a registry of its own, with its own pile ids. This
+// starts them: the ones with data when the server starts, the others on
+// first use.
+struct Workspaces {
+    storage: Storage,
+}
+
+impl Workspaces {
+    fn start_registry(&self, workspace: &str) -> Result<(), ApiError> {
+        let storage = self
+            .storage
+            .in_workspace(workspace)
+            .map_err(|err| ApiError::Storage(err.to_string()))?;
+        PileRegistry::start_as(&registry_process_name(workspace), storage)
+            .map_err(|err| ApiError::SpawnFailed(format!("{err:?}")))?;
+        Ok(())
+    }
+}
+
+#[abstract_process]
+impl Workspaces {
+    #[init]
+    fn init(_: Config<Self>, storage: Storage) -> Result<Self, ()> {
+        let names = storage
+            .list_workspaces()
+            .map_err(|err| eprintln!("Failed to list workspaces: {err}"))?;
+        let workspaces = Self { storage };
+        // piles only run while their registry does, pending handoffs and
+        // lease timers must not wait for the first request
+        for name in names {
+            workspaces
+                .start_registry(&name)
+                .map_err(|err| eprintln!("Failed to open workspace {name}: {err:?}"))?;
+        }
+        Ok(workspaces)
+    }
+
+    /// Makes sure the registry of an existing workspace runs.
+    #[handle_request]
+    fn open(&self, workspace: String) -> Result<(), ApiError> {
+        if ProcessRef::<PileRegistry>::lookup(&registry_process_name(&workspace)).is_some() {
+            return Ok(());
+        }
+        // checked first, the name becomes a path
+        if require_workspace_name(&workspace).is_err() || !self.storage.has_workspace(&workspace) {
+            return Err(ApiError::WorkspaceNotFound(workspace));
+        }
+        self.start_registry(&workspace)
+    }
+
+    #[handle_request]
+    fn create(&self, workspace: String) -> Result<(), ApiError> {
+        require_workspace_name(&workspace)?;
+        if self.storage.has_workspace(&workspace) {
+            return Err(ApiError::Conflict(format!(
+                "workspace {workspace} already exists"
+            )));
+        }
+        self.start_registry(&workspace)
+    }
+
+    #[handle_request]
+    fn list(&self) -> Result<Vec<String>, ApiError> {
+        self.storage
+            .list_workspaces()
+            .map_err(|err| ApiError::Storage(err.to_string()))
+    }
+}
+
+fn workspaces() -> Result<ProcessRef<Workspaces>, ApiError> {
+    ProcessRef::<Workspaces>::lookup(&"workspaces").ok_or(ApiError::RegistryUnavailable)
+}
+
+fn registry_process_name(workspace: &str) -> String {
+    format!("registry-{workspace}")
+}
+
+// names end up in paths and process names
+fn require_workspace_name(workspace: &str) -> Result<(), ApiError> {
+    let valid = !workspace.is_empty()
+        && workspace.len() <= 64
+        && workspace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(ApiError::BadRequest(format!(
+            "invalid workspace `{workspace}`, use up to 64 of a-z, 0-9, - and _"
+        )));
+    }
+    Ok(())
+}
+
+// =====================================
+// Errors
+// =====================================
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ApiError {
+    WorkspaceNotFound(String),
+    PileNotFound(u32),
+    TaskNotFound(u32, u32),
+    BadRequest(String),
+    RegistryUnavailable,
+    Conflict(String),
+    SpawnFailed(String),
+    Storage(String),
+    Unauthorized,
+    Forbidden(String),
+    KeyNotFound(u32),
+    KeyStoreUnavailable,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ErrorBody {
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::WorkspaceNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::PileNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::TaskNotFound(..) => StatusCode::NOT_FOUND,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::RegistryUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::SpawnFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::KeyNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::KeyStoreUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    // stable, machine readable identifier; clients match on this, not on the message
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::WorkspaceNotFound(_) => "workspace_not_found",
+            ApiError::PileNotFound(_) => "pile_not_found",
+            ApiError::TaskNotFound(..) => "task_not_found",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::RegistryUnavailable => "registry_unavailable",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::SpawnFailed(_) => "spawn_failed",
+            ApiError::Storage(_) => "storage_failed",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::KeyNotFound(_) => "key_not_found",
+            ApiError::KeyStoreUnavailable => "key_store_unavailable",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ApiError::WorkspaceNotFound(name) => format!("workspace {name} does not exist"),
+            ApiError::PileNotFound(id) => format!("pile {id} does not exist"),
+            ApiError::TaskNotFound(pile_id, task_id) => {
+                format!("task {task_id} does not exist in pile {pile_id}")
+            }
+            ApiError::BadRequest(reason) => reason.clone(),
+            ApiError::RegistryUnavailable => "pile registry is not running".to_string(),
+            ApiError::Conflict(reason) => reason.clone(),
+            ApiError::SpawnFailed(reason) => format!("failed to start pile process: {reason}"),
+            ApiError::Storage(reason) => format!("failed to persist change: {reason}"),
+            ApiError::Unauthorized => "a valid API key is required".to_string(),
+            ApiError::Forbidden(reason) => reason.clone(),
+            ApiError::KeyNotFound(id) => format!("API key {id} does not exist"),
+            ApiError::KeyStoreUnavailable => "key store is not running".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// Like `Json`, but rejects malformed bodies with an `ApiError` so that
+/// clients always get the same `{code, message}` shape back.
+pub struct ApiJson<T>(T);
+
+impl<T> FromRequest for ApiJson<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    type Rejection = ApiError;
+
+    fn from_request(req: &mut RequestContext) -> Result<Self, Self::Rejection> {
+        serde_json::from_slice(req.body().as_slice())
+            .map(ApiJson)
+            .map_err(|err| ApiError::BadRequest(format!("invalid request body: {err}")))
+    }
+}
+
+/// A body of newline delimited JSON, one `T` per line. Blank lines are
+/// skipped.
+pub struct NdJson<T>(Vec<T>);
+
+impl<T> FromRequest for NdJson<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    type Rejection = ApiError;
+
+    fn from_request(req: &mut RequestContext) -> Result<Self, Self::Rejection> {
+        let body = std::str::from_utf8(req.body().as_slice())
+            .map_err(|err| ApiError::BadRequest(format!("invalid request body: {err}")))?;
+        body.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|err| {
+                    ApiError::BadRequest(format!("invalid request body on line {}: {err}", i + 1))
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(NdJson)
+    }
+}
+
+/// A Markdown checklist body, see `markdown`.
+pub struct Checklist(Vec<Task>);
+
+impl FromRequest for Checklist {
+    type Rejection = ApiError;
+
+    fn from_request(req: &mut RequestContext) -> Result<Self, Self::Rejection> {
+        let body = std::str::from_utf8(req.body().as_slice())
+            .map_err(|err| ApiError::BadRequest(format!("invalid request body: {err}")))?;
+        markdown::parse(body)
+            .map(Checklist)
+            .map_err(ApiError::BadRequest)
+    }
+}
+
+/// A todo.txt body, one task per line. Blank lines are skipped.
+pub struct TodoTxt(Vec<Task>);
+
+impl FromRequest for TodoTxt {
+    type Rejection = ApiError;
+
+    fn from_request(req: &mut RequestContext) -> Result<Self, Self::Rejection> {
+        let body = std::str::from_utf8(req.body().as_slice())
+            .map_err(|err| ApiError::BadRequest(format!("invalid request body: {err}")))?;
+        let mut tasks = Vec::new();
+        for (i, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let task = todotxt::parse_line(line)
+                .map_err(|err| ApiError::BadRequest(format!("{err} on line {}", i + 1)))?;
+            require_title(&task.title)
+                .map_err(|_| ApiError::BadRequest(format!("line {} has no title", i + 1)))?;
+            tasks.push(task);
+        }
+        Ok(TodoTxt(tasks))
+    }
+}
+
+fn json_or_no_content<T: Serialize>(value: Option<T>) -> Response {
+    match value {
+        Some(value) => Json(value).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+// =====================================
 // Handler functions
 // =====================================
 fn liveness_check() -> &'static str {
@@ -151,26 +2494,1084 @@ fn liveness_check() -> &'static str {
     r#"{"status":"UP"}"#
 }
 
+/// The registry of `workspace`. Workspaces have to be created by an admin
+/// first.
+fn registry(caller: &Caller, workspace: &str) -> Result<ProcessRef<PileRegistry>, ApiError> {
+    if !caller.may_use(workspace) {
+        return Err(ApiError::Forbidden(format!(
+            "this API key cannot be used in workspace {workspace}"
+        )));
+    }
+    let name = registry_process_name(workspace);
+    if let Some(registry) = ProcessRef::<PileRegistry>::lookup(&name) {
+        return Ok(registry);
+    }
+    workspaces()?.open(workspace.to_string())?;
+    ProcessRef::<PileRegistry>::lookup(&name).ok_or(ApiError::RegistryUnavailable)
+}
+
+fn require_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() {
+        return Err(ApiError::BadRequest("name must not be empty".to_string()));
+    }
+    Ok(())
+}
+
+fn require_title(title: &str) -> Result<(), ApiError> {
+    if title.trim().is_empty() {
+        return Err(ApiError::BadRequest("title must not be empty".to_string()));
+    }
+    Ok(())
+}
+
+fn check_pile_limits(
+    history_depth: Option<usize>,
+    max_attempts: Option<u32>,
+) -> Result<(), ApiError> {
+    if history_depth.is_some_and(|depth| depth > MAX_HISTORY_DEPTH) {
+        return Err(ApiError::BadRequest(format!(
+            "history_depth must not exceed {MAX_HISTORY_DEPTH}"
+        )));
+    }
+    if max_attempts == Some(0) {
+        return Err(ApiError::BadRequest(
+            "max_attempts must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 // pile CRUD
-fn create_pile(Json(dto): Json<CreatePileDTO>) -> Json<PileInfo> {
-    let registry = ProcessRef::<PileRegistry>::lookup(&"registry").unwrap();
-    Json(
-        registry
-            .create_pile(dto.name, dto.description, dto.is_stack)
-            .0,
-    )
+fn create_pile(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    ApiJson(dto): ApiJson<CreatePileDTO>,
+) -> Result<Json<PileInfo>, ApiError> {
+    require_name(&dto.name)?;
+    check_pile_limits(dto.history_depth, dto.max_attempts)?;
+    let (info, _) = registry(&caller, &workspace)?.create_pile(dto, caller, None)?;
+    Ok(Json(info))
+}
+
+fn list_piles(
+    caller: Caller,
+    Path(workspace): Path<String>,
+) -> Result<Json<Vec<PileInfo>>, ApiError> {
+    Ok(Json(registry(&caller, &workspace)?.list_piles(caller)))
+}
+
+fn get_pile(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<PileInfo>, ApiError> {
+    registry(&caller, &workspace)?
+        .get_pile_info(id, caller)
+        .map(Json)
+}
+
+fn update_pile(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ApiJson(dto): ApiJson<UpdatePileDTO>,
+) -> Result<Json<PileInfo>, ApiError> {
+    if let Some(name) = &dto.name {
+        require_name(name)?;
+    }
+    check_pile_limits(None, dto.max_attempts.flatten())?;
+    registry(&caller, &workspace)?
+        .update_pile(id, dto, caller)
+        .map(Json)
+}
+
+fn delete_pile(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<StatusCode, ApiError> {
+    registry(&caller, &workspace)?.delete_pile(id, caller)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// tasks
+/// The pile, provided the caller has at least the `required` role on it.
+fn lookup_pile(
+    caller: &Caller,
+    workspace: &str,
+    id: u32,
+    required: Role,
+) -> Result<ProcessRef<Pile>, ApiError> {
+    let (pile, role) = registry(caller, workspace)?.get_pile(id, caller.clone())?;
+    require_role(role, required)?;
+    Ok(pile)
+}
+
+fn require_role(role: Role, required: Role) -> Result<(), ApiError> {
+    if role < required {
+        return Err(ApiError::Forbidden(format!(
+            "this requires the {required:?} role, the API key has {role:?}"
+        )));
+    }
+    Ok(())
+}
+
+fn push_task(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ApiJson(dto): ApiJson<CreateTaskDTO>,
+) -> Result<Json<Task>, ApiError> {
+    require_title(&dto.title)?;
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.push_task(dto)?))
+}
+
+fn insert_task(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ApiJson(dto): ApiJson<InsertTaskDTO>,
+) -> Result<Json<Task>, ApiError> {
+    require_title(&dto.task.title)?;
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.insert_task(dto.depth, dto.task)?))
+}
+
+fn move_task(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<MoveTaskDTO>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.move_task(task_id, dto.depth)?))
+}
+
+fn bury_top(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.bury_top()?))
+}
+
+fn swap_top(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.swap_top()?))
+}
+
+fn update_task(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<UpdateTaskDTO>,
+) -> Result<Json<Task>, ApiError> {
+    if let Some(title) = &dto.title {
+        require_title(title)?;
+    }
+    // finished tasks belong in the archive, which only completing leads to
+    if matches!(dto.status, Some(TaskStatus::Done | TaskStatus::Cancelled)) {
+        return Err(ApiError::BadRequest(
+            "finish a task with POST /pile/:id/complete or by acking its lease, not by setting its status"
+                .to_string(),
+        ));
+    }
+    let pile = lookup_pile(&caller, &workspace, id, Role::Contributor)?;
+    Ok(Json(pile.update_task(task_id, dto)?))
+}
+
+fn pile_top(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    Ok(json_or_no_content(pile.pile_top()))
+}
+
+const DEFAULT_NEXT_WAIT: Duration = Duration::from_secs(30);
+const MAX_NEXT_WAIT: Duration = Duration::from_secs(120);
+// how much longer than the wait itself the handler gives the pile to answer
+const NEXT_WAIT_MARGIN: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NextQuery {
+    timeout: Option<String>,
+}
+
+// accepts `30s`, `1500ms`, `2m` or a bare number of seconds
+fn parse_timeout(value: &str) -> Result<Duration, ApiError> {
+    let value = value.trim();
+    let invalid = || ApiError::BadRequest(format!("invalid timeout `{value}`"));
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+fn wait_for_task(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    Query(query): Query<NextQuery>,
+) -> Result<Response, ApiError> {
+    let wait = match query.timeout {
+        Some(timeout) => parse_timeout(&timeout)?,
+        None => DEFAULT_NEXT_WAIT,
+    };
+    if wait > MAX_NEXT_WAIT {
+        return Err(ApiError::BadRequest(format!(
+            "timeout must not exceed {}s",
+            MAX_NEXT_WAIT.as_secs()
+        )));
+    }
+    let pile = lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    // a pile that restarts meanwhile forgets its waiters and never answers,
+    // to the client that is just a wait that ran out
+    let top = pile
+        .with_timeout(wait + NEXT_WAIT_MARGIN)
+        .wait_for_task(wait)
+        .unwrap_or(None);
+    Ok(json_or_no_content(top))
+}
+
+// leases
+const DEFAULT_VISIBILITY: Duration = Duration::from_secs(30);
+const MAX_VISIBILITY: Duration = Duration::from_secs(12 * 60 * 60);
+
+fn visibility_timeout(value: Option<String>) -> Result<Duration, ApiError> {
+    let visibility = match value {
+        Some(value) => parse_timeout(&value)?,
+        None => DEFAULT_VISIBILITY,
+    };
+    if visibility.is_zero() || visibility > MAX_VISIBILITY {
+        return Err(ApiError::BadRequest(format!(
+            "visibility_timeout must be between 1ms and {}h",
+            MAX_VISIBILITY.as_secs() / 3600
+        )));
+    }
+    Ok(visibility)
+}
+
+fn claim_task(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ApiJson(dto): ApiJson<ClaimDTO>,
+) -> Result<Response, ApiError> {
+    if dto.consumer.trim().is_empty() {
+        return Err(ApiError::BadRequest(
+            "consumer must not be empty".to_string(),
+        ));
+    }
+    let visibility = visibility_timeout(dto.visibility_timeout)?;
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(json_or_no_content(pile.claim(dto.consumer, visibility)?))
+}
+
+fn ack_task(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<LeaseTokenDTO>,
+) -> Result<Json<Task>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(Json(pile.ack(task_id, dto.token)?))
+}
+
+fn nack_task(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<NackDTO>,
+) -> Result<StatusCode, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    pile.nack(task_id, dto.token, dto.reason)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn extend_lease(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<ExtendLeaseDTO>,
+) -> Result<Json<Lease>, ApiError> {
+    let visibility = visibility_timeout(dto.visibility_timeout)?;
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(Json(pile.extend(task_id, dto.token, visibility)?))
+}
+
+fn list_leases(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<Vec<Lease>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    Ok(Json(pile.list_leases()))
+}
+
+// The source pile keeps the task in its outbox until the target confirms it,
+// and the target ignores a handoff it has seen before. Whichever side dies, the
+// source's retries finish the move and the task arrives exactly once.
+fn move_to_pile(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ApiJson(dto): ApiJson<MoveToPileDTO>,
+) -> Result<Response, ApiError> {
+    if dto.target == id {
+        return Err(ApiError::BadRequest(
+            "a task cannot be moved to its own pile".to_string(),
+        ));
+    }
+    let source = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    let target = lookup_pile(&caller, &workspace, dto.target, Role::Contributor)?;
+    let (handoff, confirmed_below) = source.move_to_pile(dto.task_id, dto.target)?;
+    match target.accept_handoff(id, handoff.clone(), confirmed_below) {
+        Ok(Some(task)) => {
+            source.handoff_delivered(handoff.id);
+            Ok(Json(task).into_response())
+        }
+        // a retry was faster, the task is in the target already
+        Ok(None) => {
+            source.handoff_delivered(handoff.id);
+            Ok((StatusCode::ACCEPTED, Json(handoff)).into_response())
+        }
+        Err(err) => {
+            eprintln!(
+                "Failed to deliver handoff {} to pile {}: {err:?}",
+                handoff.id, dto.target
+            );
+            Ok((StatusCode::ACCEPTED, Json(handoff)).into_response())
+        }
+    }
+}
+
+fn complete_current(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(json_or_no_content(pile.complete_current()?))
+}
+
+fn list_tasks(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    Query(filter): Query<TaskFilter>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    let tasks = pile
+        .list_tasks()
+        .into_iter()
+        .filter(|task| filter.matches(task))
+        .collect();
+    Ok(Json(tasks))
+}
+
+fn list_done(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Page<Task>>, ApiError> {
+    if page.limit == 0 || page.limit > MAX_PAGE_LIMIT {
+        return Err(ApiError::BadRequest(format!(
+            "limit must be between 1 and {MAX_PAGE_LIMIT}"
+        )));
+    }
+    let pile = lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    Ok(Json(pile.list_done(page.offset, page.limit)))
+}
+
+fn reopen_task(
+    caller: Caller,
+    Path((workspace, id, task_id)): Path<(String, u32, u32)>,
+) -> Result<Json<Task>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(Json(pile.reopen_task(task_id)?))
+}
+
+fn undo(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(Json(pile.undo()?))
+}
+
+fn redo(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, Role::Completer)?;
+    Ok(Json(pile.redo()?))
+}
+
+// change feed
+const FEED_KEEPALIVE: Duration = Duration::from_secs(30);
+
+fn pile_feed(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    ws: WebSocket,
+) -> Result<WebSocketUpgrade, ApiError> {
+    // fail with a proper error before upgrading
+    lookup_pile(&caller, &workspace, id, Role::Viewer)?;
+    Ok(ws.on_upgrade((workspace, Some(id), caller), follow_activity))
+}
+
+/// Sends the events `caller` may see in `workspace`, or in one of its piles,
+/// down a websocket. It follows the registry's activity feed, which outlives
+/// restarts of the piles and never loses track of a connection.
+///
+/// Runs in its own process for every connection, until the client leaves or
+/// loses access.
+fn follow_activity(
+    mut conn: WebSocketConnection,
+    (workspace, pile_id, caller): (String, Option<u32>, Caller),
+) {
+    let mut after = None;
+    let mut last_write = Instant::now();
+    loop {
+        let Ok(registry) = registry(&caller, &workspace) else {
+            return;
+        };
+        // a registry that restarts meanwhile forgets its waiters, the next
+        // round asks the new one
+        let (resume_from, events) = registry
+            .with_timeout(FEED_KEEPALIVE + NEXT_WAIT_MARGIN)
+            .activity_since(after, FEED_KEEPALIVE, caller.clone())
+            .map_or((after, Vec::new()), |(id, events)| (Some(id), events));
+        after = resume_from;
+        let events = events
+            .into_iter()
+            .filter(|activity| pile_id.is_none_or(|id| activity.event.pile_id == id));
+        for activity in events {
+            let Ok(json) = serde_json::to_string(&activity.event) else {
+                continue;
+            };
+            if conn.write_message(Message::Text(json)).is_err() {
+                return;
+            }
+            last_write = Instant::now();
+            if pile_id.is_some() && activity.event.kind == PileEventKind::PileDeleted {
+                return;
+            }
+        }
+        if last_write.elapsed() < FEED_KEEPALIVE {
+            continue;
+        }
+        // tells apart a quiet feed from a client that is gone
+        if conn.write_message(Message::Ping(Vec::new())).is_err() {
+            return;
+        }
+        last_write = Instant::now();
+        // only the owner hears about a deletion, everybody else just loses
+        // access, and so does a viewer whose role was revoked
+        if let Some(pile_id) = pile_id {
+            if lookup_pile(&caller, &workspace, pile_id, Role::Viewer).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// activity stream
+const ACTIVITY_WAIT: Duration = Duration::from_secs(25);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ActivityQuery {
+    /// For clients that cannot set the `Last-Event-ID` header, like `curl`.
+    last_event_id: Option<u64>,
+}
+
+// submillisecond buffers whole responses and cannot keep a stream open, so
+// every response carries the events that are available and ends. EventSource
+// reconnects by itself and resumes from the last id it has seen. Clients that
+// need a connection that stays open use `activity_feed`.
+fn activity_stream(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<ActivityQuery>,
+) -> Result<Response, ApiError> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .or(query.last_event_id);
+
+    let (resume_from, events) =
+        registry(&caller, &workspace)?.activity_since(last_event_id, ACTIVITY_WAIT, caller);
+
+    let mut body = String::from("retry: 1000\n\n");
+    for activity in events {
+        let Ok(data) = serde_json::to_string(&activity.event) else {
+            continue;
+        };
+        body.push_str(&format!(
+            "id: {}\nevent: {}\ndata: {data}\n\n",
+            activity.id,
+            activity.event.kind.name()
+        ));
+    }
+    // also covers events the caller may not see and empty responses, so the
+    // next request does not start over
+    body.push_str(&format!("id: {resume_from}\n\n"));
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body.into_bytes())
+        .unwrap())
+}
+
+/// The activity of a workspace over a websocket, which unlike
+/// `activity_stream` stays open.
+fn activity_feed(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    ws: WebSocket,
+) -> Result<WebSocketUpgrade, ApiError> {
+    // fail with a proper error before upgrading
+    registry(&caller, &workspace)?;
+    Ok(ws.on_upgrade((workspace, None, caller), follow_activity))
+}
+
+// export and import
+/// A line of an export: a pile with everything in it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportedPile {
+    pile: PileInfo,
+    #[serde(default)]
+    tasks: Vec<Task>,
+    #[serde(default)]
+    done: Vec<Task>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImportQuery {
+    /// `preserve` keeps the exported pile ids, by default piles get new ones.
+    ids: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportedPile {
+    /// Id in the export.
+    from: u32,
+    to: u32,
+    tasks: usize,
+}
+
+// Every pile the caller can see in one workspace, one line each. Admins export
+// each workspace on its own. Piles that cannot be reached are left out and
+// listed in `X-Skipped-Piles`, rather than failing the whole export.
+fn export_piles(caller: Caller, Path(workspace): Path<String>) -> Result<Response, ApiError> {
+    let mut body = Vec::new();
+    let mut skipped = Vec::new();
+    for pile in registry(&caller, &workspace)?.list_piles(caller.clone()) {
+        let (tasks, done) = match lookup_pile(&caller, &workspace, pile.id, Role::Viewer) {
+            Ok(process) => process.export_tasks(),
+            Err(err) => {
+                eprintln!("Failed to export pile {}: {err:?}", pile.id);
+                skipped.push(pile.id.to_string());
+                continue;
+            }
+        };
+        let line = ExportedPile { pile, tasks, done };
+        serde_json::to_writer(&mut body, &line).expect("piles serialize to JSON");
+        body.push(b'\n');
+    }
+    let mut response = Response::builder().header(header::CONTENT_TYPE, "application/x-ndjson");
+    if !skipped.is_empty() {
+        response = response.header("x-skipped-piles", skipped.join(","));
+    }
+    Ok(response.body(body).unwrap())
+}
+
+// Piles are created first and wired up to their dead-letter and next piles
+// afterwards, references may point forward in the file. References to piles
+// that are not part of the import are dropped, so are roles: key ids mean
+// nothing in another deployment. The importing key owns the new piles.
+fn import_piles(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    Query(query): Query<ImportQuery>,
+    NdJson(mut lines): NdJson<ExportedPile>,
+) -> Result<Json<Vec<ImportedPile>>, ApiError> {
+    let preserve_ids = match query.ids.as_deref() {
+        None | Some("remap") => false,
+        Some("preserve") => true,
+        Some(other) => {
+            return Err(ApiError::BadRequest(format!(
+                "ids must be `preserve` or `remap`, not `{other}`"
+            )))
+        }
+    };
+    check_import(&mut lines)?;
+    let registry = registry(&caller, &workspace)?;
+    if preserve_ids {
+        let ids = lines.iter().map(|line| line.pile.id).collect();
+        if let Some(id) = registry.taken_ids(ids).first() {
+            return Err(ApiError::Conflict(format!("pile {id} exists already")));
+        }
+    }
+
+    // new pile ids by the ids in the file
+    let mut ids = HashMap::new();
+    match create_imported_piles(&registry, &caller, &lines, preserve_ids, &mut ids) {
+        Ok(imported) => Ok(Json(imported)),
+        Err(err) => {
+            // nothing of an import that failed halfway is left behind
+            let created: Vec<u32> = ids.into_values().collect();
+            for &id in &created {
+                let unlink = UpdatePileDTO {
+                    name: None,
+                    description: None,
+                    max_attempts: None,
+                    dead_letter: Some(None),
+                    on_complete: Some(None),
+                };
+                if let Err(err) = registry.update_pile(id, unlink, caller.clone()) {
+                    eprintln!("Failed to unlink pile {id} of a failed import: {err:?}");
+                }
+            }
+            for id in created {
+                if let Err(err) = registry.delete_pile(id, caller.clone()) {
+                    eprintln!("Failed to remove pile {id} of a failed import: {err:?}");
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+// everything that could fail is checked before the first pile is created
+fn check_import(lines: &mut [ExportedPile]) -> Result<(), ApiError> {
+    let mut pile_ids = HashSet::new();
+    for line in lines {
+        let pile_id = line.pile.id;
+        if !pile_ids.insert(pile_id) {
+            return Err(ApiError::BadRequest(format!(
+                "pile {pile_id} is in the file more than once"
+            )));
+        }
+        require_name(&line.pile.name)?;
+        check_pile_limits(Some(line.pile.history_depth), line.pile.max_attempts)?;
+        let mut task_ids = HashSet::new();
+        for task in line.tasks.iter_mut().chain(&mut line.done) {
+            let task_id = task.id;
+            // the pile goes on with the id after the highest one
+            if task_id == u32::MAX {
+                return Err(ApiError::BadRequest(format!(
+                    "task ids must be below {task_id}"
+                )));
+            }
+            if !task_ids.insert(task_id) {
+                return Err(ApiError::BadRequest(format!(
+                    "pile {pile_id} has task {task_id} more than once"
+                )));
+            }
+            require_title(&task.title).map_err(|_| {
+                ApiError::BadRequest(format!("task {task_id} of pile {pile_id} has no title"))
+            })?;
+            task.tags = normalize_tags(std::mem::take(&mut task.tags));
+        }
+    }
+    Ok(())
+}
+
+fn create_imported_piles(
+    registry: &ProcessRef<PileRegistry>,
+    caller: &Caller,
+    lines: &[ExportedPile],
+    preserve_ids: bool,
+    ids: &mut HashMap<u32, u32>,
+) -> Result<Vec<ImportedPile>, ApiError> {
+    let mut imported = Vec::new();
+    for line in lines {
+        let dto = CreatePileDTO {
+            name: line.pile.name.clone(),
+            description: line.pile.description.clone(),
+            policy: line.pile.policy,
+            history_depth: Some(line.pile.history_depth),
+            max_attempts: line.pile.max_attempts,
+            dead_letter: None,
+            on_complete: None,
+        };
+        let id = preserve_ids.then_some(line.pile.id);
+        let (info, pile) = registry.create_pile(dto, caller.clone(), id)?;
+        ids.insert(line.pile.id, info.id);
+        pile.load_tasks(line.tasks.clone(), line.done.clone())?;
+        imported.push(ImportedPile {
+            from: line.pile.id,
+            to: info.id,
+            tasks: line.tasks.len() + line.done.len(),
+        });
+    }
+
+    for line in lines {
+        let dead_letter = line.pile.dead_letter.and_then(|id| ids.get(&id).copied());
+        let on_complete = line.pile.on_complete.clone().and_then(|next| {
+            let pile_id = *ids.get(&next.pile_id)?;
+            Some(CompletionTarget { pile_id, ..next })
+        });
+        if dead_letter.is_none() && on_complete.is_none() {
+            continue;
+        }
+        let update = UpdatePileDTO {
+            name: None,
+            description: None,
+            max_attempts: None,
+            dead_letter: Some(dead_letter),
+            on_complete: Some(on_complete),
+        };
+        registry.update_pile(ids[&line.pile.id], update, caller.clone())?;
+    }
+    Ok(imported)
+}
+
+// todo.txt and Markdown
+/// The pile to create when importing a todo.txt file or a checklist.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NewPileQuery {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    policy: OrderingPolicy,
+}
+
+// open tasks in insertion order, then the archive, so that importing the
+// file again rebuilds the pile as it is
+fn export_todo_txt(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    let (tasks, done) = lookup_pile(&caller, &workspace, id, Role::Viewer)?.export_tasks();
+    let mut body = String::new();
+    for task in tasks.iter().chain(&done) {
+        body.push_str(&todotxt::to_line(task));
+        body.push('\n');
+    }
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(body.into_bytes())
+        .unwrap())
+}
+
+fn import_todo_txt(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    TodoTxt(tasks): TodoTxt,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, import_role(&tasks))?;
+    Ok(Json(pile.import_tasks(tasks, false)?))
+}
+
+// finished tasks go straight into the archive, like completing them would
+fn import_role(tasks: &[Task]) -> Role {
+    let finished = tasks
+        .iter()
+        .any(|task| matches!(task.status, TaskStatus::Done | TaskStatus::Cancelled));
+    if finished {
+        Role::Completer
+    } else {
+        Role::Contributor
+    }
+}
+
+// the same, into a new pile described by the query
+fn import_todo_txt_pile(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    Query(query): Query<NewPileQuery>,
+    TodoTxt(tasks): TodoTxt,
+) -> Result<Json<PileInfo>, ApiError> {
+    let (info, pile) = create_pile_from(&caller, &workspace, query)?;
+    pile.import_tasks(tasks, false)?;
+    Ok(Json(info))
+}
+
+// open tasks top first, as they would be handed out, then the archive
+fn export_checklist(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+) -> Result<Response, ApiError> {
+    let info = registry(&caller, &workspace)?.get_pile_info(id, caller.clone())?;
+    let (tasks, done) = lookup_pile(&caller, &workspace, id, Role::Viewer)?.export_tasks();
+    let tasks: VecDeque<Task> = tasks.into();
+    let tasks: Vec<Task> = info
+        .policy
+        .pop_order(info.id, &tasks)
+        .into_iter()
+        .map(|index| tasks[index].clone())
+        .collect();
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/markdown; charset=utf-8")
+        .body(markdown::render(&info.name, &tasks, &done).into_bytes())
+        .unwrap())
+}
+
+fn import_checklist(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    Checklist(tasks): Checklist,
+) -> Result<Json<Vec<Task>>, ApiError> {
+    let pile = lookup_pile(&caller, &workspace, id, import_role(&tasks))?;
+    Ok(Json(pile.import_tasks(tasks, true)?))
+}
+
+fn import_checklist_pile(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    Query(query): Query<NewPileQuery>,
+    Checklist(tasks): Checklist,
+) -> Result<Json<PileInfo>, ApiError> {
+    let (info, pile) = create_pile_from(&caller, &workspace, query)?;
+    pile.import_tasks(tasks, true)?;
+    Ok(Json(info))
+}
+
+fn create_pile_from(
+    caller: &Caller,
+    workspace: &str,
+    query: NewPileQuery,
+) -> Result<(PileInfo, ProcessRef<Pile>), ApiError> {
+    require_name(&query.name)?;
+    let dto = CreatePileDTO {
+        name: query.name,
+        description: query.description,
+        policy: query.policy,
+        history_depth: None,
+        max_attempts: None,
+        dead_letter: None,
+        on_complete: None,
+    };
+    registry(caller, workspace)?.create_pile(dto, caller.clone(), None)
+}
+
+// calendar feeds
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CalendarQuery {
+    #[serde(default)]
+    component: ical::Component,
+}
+
+fn pile_calendar(
+    caller: Caller,
+    Path((workspace, id)): Path<(String, u32)>,
+    Query(query): Query<CalendarQuery>,
+) -> Result<Response, ApiError> {
+    let info = registry(&caller, &workspace)?.get_pile_info(id, caller.clone())?;
+    let (tasks, _) = lookup_pile(&caller, &workspace, id, Role::Viewer)?.export_tasks();
+    let name = info.name.clone();
+    Ok(calendar_response(ical::calendar(
+        &name,
+        &workspace,
+        query.component,
+        &[(info, tasks)],
+    )))
+}
+
+// every pile the caller can see. A pile that cannot be reached is left out
+// rather than failing the feed, it is back on the next refresh.
+fn workspace_calendar(
+    caller: Caller,
+    Path(workspace): Path<String>,
+    Query(query): Query<CalendarQuery>,
+) -> Result<Response, ApiError> {
+    let mut piles = Vec::new();
+    for info in registry(&caller, &workspace)?.list_piles(caller.clone()) {
+        let (tasks, _) = match lookup_pile(&caller, &workspace, info.id, Role::Viewer) {
+            Ok(process) => process.export_tasks(),
+            Err(err) => {
+                eprintln!("Failed to add pile {} to the calendar: {err:?}", info.id);
+                continue;
+            }
+        };
+        piles.push((info, tasks));
+    }
+    Ok(calendar_response(ical::calendar(
+        &workspace,
+        &workspace,
+        query.component,
+        &piles,
+    )))
+}
+
+fn calendar_response(ics: String) -> Response {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+        .body(ics.into_bytes())
+        .unwrap()
+}
+
+// roles
+fn grant_role(
+    caller: Caller,
+    Path((workspace, id, key_id)): Path<(String, u32, u32)>,
+    ApiJson(dto): ApiJson<GrantDTO>,
+) -> Result<Json<PileInfo>, ApiError> {
+    if !key_store()?.list_keys().iter().any(|key| key.id == key_id) {
+        return Err(ApiError::KeyNotFound(key_id));
+    }
+    registry(&caller, &workspace)?
+        .grant_role(id, key_id, dto.role, caller)
+        .map(Json)
+}
+
+fn revoke_role(
+    caller: Caller,
+    Path((workspace, id, key_id)): Path<(String, u32, u32)>,
+) -> Result<StatusCode, ApiError> {
+    registry(&caller, &workspace)?.revoke_role(id, key_id, caller)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// API keys
+fn require_admin(caller: &Caller) -> Result<(), ApiError> {
+    if !caller.is_global_admin() {
+        return Err(ApiError::Forbidden(
+            "an admin API key that is not tied to a workspace is required".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn list_keys(caller: Caller) -> Result<Json<Vec<KeyInfo>>, ApiError> {
+    require_admin(&caller)?;
+    Ok(Json(key_store()?.list_keys()))
+}
+
+// the only response that contains the secret
+fn create_key(
+    caller: Caller,
+    ApiJson(dto): ApiJson<CreateKeyDTO>,
+) -> Result<Json<ApiKey>, ApiError> {
+    require_admin(&caller)?;
+    require_name(&dto.name)?;
+    if let Some(workspace) = &dto.workspace {
+        require_workspace_name(workspace)?;
+    }
+    Ok(Json(key_store()?.create_key(dto)?))
+}
+
+// workspaces
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorkspaceDTO {
+    name: String,
+}
+
+fn list_workspaces(caller: Caller) -> Result<Json<Vec<String>>, ApiError> {
+    require_admin(&caller)?;
+    workspaces()?.list().map(Json)
+}
+
+fn create_workspace(
+    caller: Caller,
+    ApiJson(dto): ApiJson<WorkspaceDTO>,
+) -> Result<Json<WorkspaceDTO>, ApiError> {
+    require_admin(&caller)?;
+    workspaces()?.create(dto.name.clone())?;
+    Ok(Json(dto))
+}
+
+// piles of a revoked key are left to the admins, the roles it was given on
+// other piles go with it
+fn revoke_key(caller: Caller, Path(key_id): Path<u32>) -> Result<StatusCode, ApiError> {
+    require_admin(&caller)?;
+    key_store()?.revoke_key(key_id)?;
+    // key ids are never reused, a role that is left behind grants nothing
+    for workspace in workspaces()?.list()? {
+        let forgotten = registry(&caller, &workspace).and_then(|r| r.forget_key(key_id));
+        if let Err(err) = forgotten {
+            eprintln!("Failed to drop the roles of API key {key_id} in {workspace}: {err:?}");
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
 
 // =====================================
 // Router and app initialisation
 // =====================================
+
+// older routes work on the default workspace, whose data stays right in the
+// data directory where it always was
+fn default_workspace(mut req: RequestContext) -> Response {
+    req.params_mut().push("workspace", DEFAULT_WORKSPACE);
+    req.next_handler()
+}
+
+// the routes of one workspace, below `/api/w/:workspace`
+const WORKSPACE_ROUTER: Router = router! {
+    GET "/events" => activity_stream
+    GET "/events/ws" => activity_feed
+    GET "/calendar.ics" => workspace_calendar
+
+    GET "/export" => export_piles
+    POST "/import" => import_piles
+    POST "/import/todo.txt" => import_todo_txt_pile
+    POST "/import/checklist.md" => import_checklist_pile
+
+    GET "/pile" => list_piles
+    POST "/pile" => create_pile
+    GET "/pile/:id" => get_pile
+    PATCH "/pile/:id" => update_pile
+    DELETE "/pile/:id" => delete_pile
+
+    GET "/pile/:id/tasks" => list_tasks
+    POST "/pile/:id/tasks" => push_task
+    POST "/pile/:id/tasks/insert" => insert_task
+    PATCH "/pile/:id/tasks/:task_id" => update_task
+    POST "/pile/:id/tasks/:task_id/move" => move_task
+    POST "/pile/:id/bury" => bury_top
+    POST "/pile/:id/swap" => swap_top
+    POST "/pile/:id/move" => move_to_pile
+    GET "/pile/:id/top" => pile_top
+    GET "/pile/:id/next" => wait_for_task
+    POST "/pile/:id/claim" => claim_task
+    GET "/pile/:id/leases" => list_leases
+    POST "/pile/:id/leases/:task_id/ack" => ack_task
+    POST "/pile/:id/leases/:task_id/nack" => nack_task
+    POST "/pile/:id/leases/:task_id/extend" => extend_lease
+    POST "/pile/:id/complete" => complete_current
+    GET "/pile/:id/done" => list_done
+    POST "/pile/:id/done/:task_id/reopen" => reopen_task
+    POST "/pile/:id/undo" => undo
+    POST "/pile/:id/redo" => redo
+    GET "/pile/:id/ws" => pile_feed
+    GET "/pile/:id/todo.txt" => export_todo_txt
+    POST "/pile/:id/todo.txt" => import_todo_txt
+    GET "/pile/:id/checklist.md" => export_checklist
+    GET "/pile/:id/calendar.ics" => pile_calendar
+    POST "/pile/:id/checklist.md" => import_checklist
+    PUT "/pile/:id/acl/:key_id" => grant_role
+    DELETE "/pile/:id/acl/:key_id" => revoke_role
+};
+
 const ROUTER: Router = router! {
+    with authenticate;
+
     "/api/alive" => liveness_check
+    "/api/w/:workspace" => WORKSPACE_ROUTER
+
+    GET "/api/admin/keys" => list_keys
+    POST "/api/admin/keys" => create_key
+    DELETE "/api/admin/keys/:key_id" => revoke_key
+    GET "/api/admin/workspaces" => list_workspaces
+    POST "/api/admin/workspaces" => create_workspace
 
-    POST "/api/pile" => create_pile
+    // the routes from before there were workspaces, like `/api/pile/:id`
+    "/api" with default_workspace => WORKSPACE_ROUTER
 };
 
 fn main() -> std::io::Result<()> {
-    let _registry = PileRegistry::start_as(&"registry", ()).expect("should initialize registry");
+    let storage = Storage::from_env()?;
+    let admin_key = std::env::var("ADMIN_API_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty());
+    let _keys =
+        KeyStore::start_as(&"keys", (storage.clone(), admin_key)).expect("should load API keys");
+    let _workspaces = Workspaces::start_as(&"workspaces", storage).expect("should open workspaces");
     Application::new(ROUTER).serve("0.0.0.0:3000")
 }
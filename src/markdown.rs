@@ -0,0 +1,238 @@
+//! Piles as Markdown checklists.
+//!
+//! ```markdown
+//! # Launch
+//!
+//! - [ ] Write the announcement
+//!   - ask legal about the wording
+//! - [x] Book the venue
+//! ```
+//!
+//! Every top-level item is a task, checked ones are finished. There are no
+//! subtasks, whatever is nested below an item becomes its description.
+//! Headings and other text between the items are skipped.
+
+use chrono::Utc;
+
+use crate::{Task, TaskStatus};
+
+pub fn render(name: &str, tasks: &[Task], done: &[Task]) -> String {
+    let mut markdown = format!("# {name}\n\n");
+    for task in tasks {
+        render_item(&mut markdown, task, false);
+    }
+    for task in done {
+        render_item(&mut markdown, task, true);
+    }
+    markdown
+}
+
+fn render_item(markdown: &mut String, task: &Task, checked: bool) {
+    let check = if checked { 'x' } else { ' ' };
+    markdown.push_str(&format!("- [{check}] {}\n", task.title));
+    let mut lines = task.description.lines().peekable();
+    // plain text right below the item would run into its title
+    if lines.peek().is_some_and(|line| list_item(line).is_none()) {
+        markdown.push('\n');
+    }
+    for line in lines {
+        if !line.trim().is_empty() {
+            markdown.push_str("  ");
+            markdown.push_str(line);
+        }
+        markdown.push('\n');
+    }
+}
+
+// a task being collected, with the column its text starts at
+struct Item {
+    task: Task,
+    column: usize,
+    description: Vec<String>,
+}
+
+impl Item {
+    fn finish(mut self) -> Task {
+        let lines = &self.description;
+        let start = lines.iter().position(|line| !line.trim().is_empty());
+        let end = lines.iter().rposition(|line| !line.trim().is_empty());
+        if let (Some(start), Some(end)) = (start, end) {
+            self.task.description = lines[start..=end].join("\n");
+        }
+        self.task
+    }
+}
+
+/// Reads the tasks of a checklist, in the order they are listed.
+pub fn parse(markdown: &str) -> Result<Vec<Task>, String> {
+    let mut tasks = Vec::new();
+    let mut top_level = None;
+    let mut item: Option<Item> = None;
+    for (i, line) in markdown.lines().enumerate() {
+        let line = line.replace('\t', "    ");
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let nested = line.trim().is_empty() || top_level.is_some_and(|top| indent > top);
+        match list_item(&line) {
+            Some((indent, column, text)) if top_level.is_none_or(|top| indent <= top) => {
+                tasks.extend(item.take().map(Item::finish));
+                top_level.get_or_insert(indent);
+                let task = new_task(text).ok_or_else(|| format!("line {} has no title", i + 1))?;
+                item = Some(Item {
+                    task,
+                    column,
+                    description: Vec::new(),
+                });
+            }
+            _ if nested => {
+                if let Some(item) = item.as_mut() {
+                    let dedent = indent.min(item.column);
+                    item.description.push(line[dedent..].trim_end().to_string());
+                }
+            }
+            _ => tasks.extend(item.take().map(Item::finish)),
+        }
+    }
+    tasks.extend(item.map(Item::finish));
+    Ok(tasks)
+}
+
+// the indentation, the column the text starts at and the text of a list item
+fn list_item(line: &str) -> Option<(usize, usize, &str)> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    let marker = if rest.starts_with(&['-', '*', '+'][..]) {
+        1
+    } else {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 || !rest[digits..].starts_with(&['.', ')'][..]) {
+            return None;
+        }
+        digits + 1
+    };
+    let after = &rest[marker..];
+    let text = after.trim_start_matches(' ');
+    // `---` is a rule, `-foo` just text
+    if text.len() == after.len() && !after.is_empty() {
+        return None;
+    }
+    Some((indent, indent + marker + after.len() - text.len(), text))
+}
+
+fn new_task(text: &str) -> Option<Task> {
+    let (checked, title) = match text.get(..3) {
+        Some("[ ]") => (false, &text[3..]),
+        Some("[x]" | "[X]") => (true, &text[3..]),
+        _ => (false, text),
+    };
+    let title = title.trim();
+    if title.is_empty() {
+        return None;
+    }
+    let now = Utc::now();
+    Some(Task {
+        title: title.to_string(),
+        status: if checked {
+            TaskStatus::Done
+        } else {
+            TaskStatus::Open
+        },
+        created_at: Some(now),
+        updated_at: Some(now),
+        completed_at: checked.then_some(now),
+        ..Task::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(title: &str, description: &str, status: TaskStatus) -> Task {
+        Task {
+            description: description.to_string(),
+            status,
+            ..new_task(title).unwrap()
+        }
+    }
+
+    fn summary(tasks: &[Task]) -> Vec<(String, String, TaskStatus)> {
+        tasks
+            .iter()
+            .map(|task| (task.title.clone(), task.description.clone(), task.status))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let tasks = [
+            task("Write the announcement", "", TaskStatus::Open),
+            task(
+                "Plan",
+                "- ask legal\n  - about wording\n- ask marketing",
+                TaskStatus::Open,
+            ),
+            task(
+                "Notes",
+                "first paragraph\n\nsecond paragraph",
+                TaskStatus::InProgress,
+            ),
+            task("Code", "    indented\nplain", TaskStatus::Open),
+        ];
+        let done = [task("Book the venue", "for 200 people", TaskStatus::Done)];
+        let markdown = render("Launch", &tasks, &done);
+        let parsed = parse(&markdown).unwrap();
+
+        let mut expected = summary(&tasks);
+        // there is no box for in progress, an open box is as close as it gets
+        expected[2].2 = TaskStatus::Open;
+        expected.extend(summary(&done));
+        assert_eq!(summary(&parsed), expected, "{markdown}");
+        assert!(parsed[4].completed_at.is_some());
+    }
+
+    #[test]
+    fn planning_notes() {
+        let markdown = "\
+# Sprint 12
+
+Some intro text.
+
+1. First thing
+   more about it
+2) [x] Second
+- Third
+  * nested a
+\t* nested b
+
+---
+- [ ] Fourth
+";
+        let parsed = parse(markdown).unwrap();
+        assert_eq!(
+            summary(&parsed),
+            [
+                (
+                    "First thing".to_string(),
+                    "more about it".to_string(),
+                    TaskStatus::Open
+                ),
+                ("Second".to_string(), String::new(), TaskStatus::Done),
+                (
+                    "Third".to_string(),
+                    "* nested a\n  * nested b".to_string(),
+                    TaskStatus::Open
+                ),
+                ("Fourth".to_string(), String::new(), TaskStatus::Open),
+            ]
+        );
+    }
+
+    #[test]
+    fn items_need_a_title() {
+        assert_eq!(
+            parse("- [ ] fine\n- [ ]\n").unwrap_err(),
+            "line 2 has no title"
+        );
+        assert!(parse("no list here\n").unwrap().is_empty());
+    }
+}
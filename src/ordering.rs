@@ -0,0 +1,123 @@
+use std::{cmp::Ordering, collections::VecDeque};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::Task;
+
+/// Decides which task a pile hands out next.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrderingPolicy {
+    /// Last in, first out. What `is_stack: true` used to mean.
+    #[default]
+    Lifo,
+    /// First in, first out. What `is_stack: false` used to mean.
+    Fifo,
+    /// Most urgent priority first, tasks without a priority last.
+    Priority,
+    /// Earliest due date first, tasks without a due date last.
+    EarliestDue,
+    /// A shuffled, but stable, order.
+    Random,
+}
+
+impl OrderingPolicy {
+    /// Only stacks and queues give every task a fixed position, the other
+    /// policies decide the order on their own.
+    pub fn is_positional(&self) -> bool {
+        matches!(self, OrderingPolicy::Lifo | OrderingPolicy::Fifo)
+    }
+
+    /// Turns a depth below the top of a positional pile into an index in
+    /// insertion order. `len` is the length of the pile once the task is in
+    /// place, depths past the bottom end up at the bottom.
+    pub fn depth_to_index(&self, depth: usize, len: usize) -> usize {
+        let depth = depth.min(len.saturating_sub(1));
+        match self {
+            OrderingPolicy::Lifo => len.saturating_sub(1) - depth,
+            _ => depth,
+        }
+    }
+}
+
+/// Orders the tasks of a pile. `tasks` is always in insertion order.
+pub trait TaskOrdering {
+    /// `Less` means `a` is handed out before `b`. The pile id is mixed into
+    /// the random order, so that two piles do not shuffle the same way.
+    fn compare(&self, pile_id: u32, a: (usize, &Task), b: (usize, &Task)) -> Ordering;
+
+    /// Indices into `tasks`, in the order they would be handed out.
+    fn pop_order(&self, pile_id: u32, tasks: &VecDeque<Task>) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..tasks.len()).collect();
+        order.sort_by(|&a, &b| self.compare(pile_id, (a, &tasks[a]), (b, &tasks[b])));
+        order
+    }
+
+    /// Index of the task that is handed out next.
+    fn next_index(&self, pile_id: u32, tasks: &VecDeque<Task>) -> Option<usize> {
+        (0..tasks.len()).min_by(|&a, &b| self.compare(pile_id, (a, &tasks[a]), (b, &tasks[b])))
+    }
+}
+
+impl TaskOrdering for OrderingPolicy {
+    fn compare(&self, pile_id: u32, a: (usize, &Task), b: (usize, &Task)) -> Ordering {
+        let (a_index, a) = a;
+        let (b_index, b) = b;
+        let fifo = a_index.cmp(&b_index);
+        match self {
+            OrderingPolicy::Lifo => fifo.reverse(),
+            OrderingPolicy::Fifo => fifo,
+            OrderingPolicy::Priority => missing_last(a.priority, b.priority).then(fifo),
+            OrderingPolicy::EarliestDue => missing_last(a.due_at, b.due_at)
+                .then(missing_last(a.priority, b.priority))
+                .then(fifo),
+            OrderingPolicy::Random => shuffle_key(pile_id, a.id)
+                .cmp(&shuffle_key(pile_id, b.id))
+                .then(fifo),
+        }
+    }
+}
+
+fn missing_last<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+// splitmix64; a task keeps its place in the shuffle for as long as it is in
+// the pile, so peeking at the top and completing it always agree
+fn shuffle_key(pile_id: u32, task_id: u32) -> u64 {
+    let mut z = (((pile_id as u64) << 32) | task_id as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PolicyOrIsStack {
+    Policy(OrderingPolicy),
+    IsStack(bool),
+}
+
+/// Accepts a policy name as well as the old `is_stack` flag. Use together with
+/// `#[serde(alias = "is_stack")]`.
+///
+/// Only JSON needs the fallback, messages between processes are bincode,
+/// which cannot handle untagged enums and always carries the policy.
+pub fn deserialize_policy<'de, D>(deserializer: D) -> Result<OrderingPolicy, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if !deserializer.is_human_readable() {
+        return OrderingPolicy::deserialize(deserializer);
+    }
+    Ok(match PolicyOrIsStack::deserialize(deserializer)? {
+        PolicyOrIsStack::Policy(policy) => policy,
+        PolicyOrIsStack::IsStack(true) => OrderingPolicy::Lifo,
+        PolicyOrIsStack::IsStack(false) => OrderingPolicy::Fifo,
+    })
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    auth::StoredKey, wal::WriteAheadLog, Handoff, Lease, PileInfo, PileOp, RegistryOp, Task,
+};
+
+const DEFAULT_DATA_DIR: &str = "data";
+
+/// Workspace whose data sits right in the data directory, where all data was
+/// kept before there were workspaces.
+pub const DEFAULT_WORKSPACE: &str = "default";
+
+// =====================================
+// Snapshots
+// =====================================
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RegistrySnapshot {
+    /// Sequence number of the last log record folded into this snapshot.
+    #[serde(default)]
+    pub seq: u64,
+    pub counter: u32,
+    pub piles: Vec<PileInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PileSnapshot {
+    /// Sequence number of the last log record folded into this snapshot.
+    #[serde(default)]
+    pub seq: u64,
+    /// The pile's own copy of its info, which may be newer than the one its
+    /// supervisor starts it with.
+    #[serde(default)]
+    pub info: Option<PileInfo>,
+    pub next_task_id: u32,
+    pub tasks: VecDeque<Task>,
+    #[serde(default)]
+    pub done: Vec<Task>,
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+    #[serde(default)]
+    pub next_lease_token: u64,
+    #[serde(default)]
+    pub outbox: Vec<Handoff>,
+    #[serde(default)]
+    pub next_handoff_id: u64,
+    #[serde(default)]
+    pub received: HashMap<u32, BTreeSet<u64>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KeysSnapshot {
+    pub next_id: u32,
+    pub keys: Vec<StoredKey>,
+}
+
+// =====================================
+// Storage
+// =====================================
+
+/// Handle to the data of one workspace. It is cheap to clone and
+/// serializable, so the registry can pass it on to every pile it spawns.
+///
+/// The directory is taken from `DATA_DIR` (defaults to `./data`) and has to be
+/// made available to the VM, e.g. `lunatic run --dir . app.wasm`. Workspaces
+/// other than the default one live in `workspaces/<name>` below it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Storage {
+    base: PathBuf,
+    workspace: String,
+    root: PathBuf,
+}
+
+impl Storage {
+    pub fn from_env() -> io::Result<Self> {
+        let base = std::env::var("DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_string());
+        Self::open(base, DEFAULT_WORKSPACE)
+    }
+
+    pub fn open(base: impl Into<PathBuf>, workspace: &str) -> io::Result<Self> {
+        let base = base.into();
+        let root = if workspace == DEFAULT_WORKSPACE {
+            base.clone()
+        } else {
+            base.join("workspaces").join(workspace)
+        };
+        fs::create_dir_all(root.join("piles"))?;
+        Ok(Self {
+            base,
+            workspace: workspace.to_string(),
+            root,
+        })
+    }
+
+    /// The same data directory, scoped to another workspace.
+    pub fn in_workspace(&self, workspace: &str) -> io::Result<Self> {
+        Self::open(self.base.clone(), workspace)
+    }
+
+    pub fn workspace(&self) -> &str {
+        &self.workspace
+    }
+
+    pub fn has_workspace(&self, workspace: &str) -> bool {
+        workspace == DEFAULT_WORKSPACE || self.base.join("workspaces").join(workspace).is_dir()
+    }
+
+    /// Every workspace that has data, the default one included.
+    pub fn list_workspaces(&self) -> io::Result<Vec<String>> {
+        let mut workspaces = vec![DEFAULT_WORKSPACE.to_string()];
+        let entries = match fs::read_dir(self.base.join("workspaces")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(workspaces),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    workspaces.push(name.to_string());
+                }
+            }
+        }
+        Ok(workspaces)
+    }
+
+    pub fn load_registry(&self) -> io::Result<Option<RegistrySnapshot>> {
+        read_json(self.registry_path())
+    }
+
+    pub fn save_registry(&self, snapshot: &RegistrySnapshot) -> io::Result<()> {
+        write_json(self.registry_path(), snapshot)
+    }
+
+    pub fn registry_log(&self) -> WriteAheadLog<RegistryOp> {
+        WriteAheadLog::new(self.root.join("registry.log"))
+    }
+
+    pub fn load_keys(&self) -> io::Result<Option<KeysSnapshot>> {
+        read_json(self.base.join("keys.json"))
+    }
+
+    pub fn save_keys(&self, snapshot: &KeysSnapshot) -> io::Result<()> {
+        write_json(self.base.join("keys.json"), snapshot)
+    }
+
+    pub fn load_pile(&self, pile_id: u32) -> io::Result<Option<PileSnapshot>> {
+        read_json(self.pile_path(pile_id))
+    }
+
+    pub fn save_pile(&self, pile_id: u32, snapshot: &PileSnapshot) -> io::Result<()> {
+        write_json(self.pile_path(pile_id), snapshot)
+    }
+
+    pub fn pile_log(&self, pile_id: u32) -> WriteAheadLog<PileOp> {
+        WriteAheadLog::new(self.root.join("piles").join(format!("{pile_id}.log")))
+    }
+
+    pub fn remove_pile(&self, pile_id: u32) -> io::Result<()> {
+        self.pile_log(pile_id).truncate()?;
+        match fs::remove_file(self.pile_path(pile_id)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn registry_path(&self) -> PathBuf {
+        self.root.join("registry.json")
+    }
+
+    fn pile_path(&self, pile_id: u32) -> PathBuf {
+        self.root.join("piles").join(format!("{pile_id}.json"))
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: PathBuf) -> io::Result<Option<T>> {
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// write to a temp file first and rename it over the old one, so a crash
+// mid-write never leaves a half written snapshot behind
+fn write_json<T: Serialize>(path: PathBuf, value: &T) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let bytes =
+        serde_json::to_vec(value).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,485 @@
+//! Tasks as [todo.txt](https://github.com/todotxt/todo.txt) lines.
+//!
+//! ```text
+//! x (A) 2024-03-02 2024-03-01 Call mom +family @phone due:2024-03-05
+//! ```
+//!
+//! Priorities 0 to 25 are `(A)` to `(Z)`, tags are `+projects`, except for
+//! tags starting with `@`, which are contexts. Everything todo.txt has no
+//! place for goes into `key:value` pairs (`status:`, `description:`, exact
+//! timestamps, ...), so that a task survives an export and import unchanged,
+//! apart from its id. Values and words that would be mistaken for any of
+//! this are escaped.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, TimeZone, Utc};
+
+use crate::{is_midnight, Task, TaskFailure, TaskStatus};
+
+// keys we read back, any other `key:value` is just a word of the title
+const KEYS: [&str; 10] = [
+    "due",
+    "pri",
+    "status",
+    "created",
+    "completed",
+    "updated",
+    "attempts",
+    "failed",
+    "description",
+    "title",
+];
+
+pub fn to_line(task: &Task) -> String {
+    let mut parts = Vec::new();
+    let mut keys = Vec::new();
+    let done = matches!(task.status, TaskStatus::Done | TaskStatus::Cancelled);
+
+    if done {
+        parts.push("x".to_string());
+    }
+    match task.priority {
+        Some(priority) if priority < 26 && !done => {
+            parts.push(format!("({})", (b'A' + priority) as char))
+        }
+        Some(priority) => keys.push(format!("pri:{}", priority_name(priority))),
+        None => {}
+    }
+    // a single date after `x` is the completion date, so a completed task
+    // without one keeps its creation date in a key instead
+    let created_slot = !done || task.completed_at.is_some();
+    if done {
+        if let Some(completed_at) = task.completed_at {
+            parts.push(date(completed_at));
+        }
+    }
+    if created_slot {
+        if let Some(created_at) = task.created_at {
+            parts.push(date(created_at));
+        }
+    }
+
+    let words: Vec<&str> = task.title.split_whitespace().collect();
+    if words.join(" ") == task.title {
+        for (i, word) in words.iter().enumerate() {
+            parts.push(escape_word(word, i == 0));
+        }
+    } else {
+        // whitespace the line would not keep
+        keys.push(format!("title:{}", escape(&task.title)));
+    }
+
+    for tag in &task.tags {
+        if tag.len() > 1 && tag.starts_with('@') {
+            parts.push(escape(tag));
+        } else {
+            parts.push(format!("+{}", escape(tag)));
+        }
+    }
+    if let Some(due_at) = task.due_at {
+        parts.push(format!("due:{}", date_or_timestamp(due_at)));
+    }
+    match task.status {
+        TaskStatus::InProgress => keys.push("status:in-progress".to_string()),
+        TaskStatus::Cancelled => keys.push("status:cancelled".to_string()),
+        TaskStatus::Open | TaskStatus::Done => {}
+    }
+    if let Some(created_at) = task.created_at {
+        if !created_slot || !is_midnight(created_at) {
+            keys.push(format!("created:{}", timestamp(created_at)));
+        }
+    }
+    if let Some(completed_at) = task.completed_at {
+        if !done || !is_midnight(completed_at) {
+            keys.push(format!("completed:{}", timestamp(completed_at)));
+        }
+    }
+    if let Some(updated_at) = task.updated_at {
+        keys.push(format!("updated:{}", timestamp(updated_at)));
+    }
+    if task.attempts > 0 {
+        keys.push(format!("attempts:{}", task.attempts));
+    }
+    for failure in &task.failures {
+        keys.push(format!(
+            "failed:{}/{}",
+            timestamp(failure.at),
+            escape(&failure.reason)
+        ));
+    }
+    if !task.description.is_empty() {
+        keys.push(format!("description:{}", escape(&task.description)));
+    }
+
+    parts.extend(keys);
+    parts.join(" ")
+}
+
+/// Reads a task from a line. The task gets id 0, the pile it goes into
+/// hands out the real one.
+pub fn parse_line(line: &str) -> Result<Task, String> {
+    let mut tokens = line.split_whitespace().peekable();
+    let mut task = Task::default();
+
+    let done = tokens.next_if_eq(&"x").is_some();
+    if done {
+        task.status = TaskStatus::Done;
+    }
+    if let Some(priority) = tokens.peek().and_then(|token| parse_priority(token)) {
+        task.priority = Some(priority);
+        tokens.next();
+    }
+    let mut dates = Vec::new();
+    while dates.len() < 2 {
+        match tokens.peek().and_then(|token| parse_date(token)) {
+            Some(date) => {
+                dates.push(date);
+                tokens.next();
+            }
+            None => break,
+        }
+    }
+    match (done, dates.as_slice()) {
+        (true, [completed_at, created_at]) => {
+            task.completed_at = Some(*completed_at);
+            task.created_at = Some(*created_at);
+        }
+        (true, [completed_at]) => task.completed_at = Some(*completed_at),
+        (false, [created_at, ..]) => task.created_at = Some(*created_at),
+        _ => {}
+    }
+
+    let mut words = Vec::new();
+    let mut title = None;
+    for token in tokens {
+        if let Some(word) = token.strip_prefix('\\') {
+            words.push(word.to_string());
+        } else if let Some(tag) = token.strip_prefix('+').filter(|tag| !tag.is_empty()) {
+            task.tags.push(unescape(tag));
+        } else if token.len() > 1 && token.starts_with('@') {
+            task.tags.push(unescape(token));
+        } else if let Some((key, value)) = known_pair(token) {
+            match key {
+                "due" => task.due_at = Some(parse_date_or_timestamp(value)?),
+                "pri" => task.priority = Some(parse_priority_name(value)?),
+                "status" => task.status = parse_status(value)?,
+                "created" => task.created_at = Some(parse_timestamp(value)?),
+                "completed" => task.completed_at = Some(parse_timestamp(value)?),
+                "updated" => task.updated_at = Some(parse_timestamp(value)?),
+                "attempts" => {
+                    task.attempts = value
+                        .parse()
+                        .map_err(|_| format!("invalid attempts `{value}`"))?
+                }
+                "failed" => {
+                    let (at, reason) = value
+                        .split_once('/')
+                        .ok_or_else(|| format!("invalid failure `{value}`"))?;
+                    task.failures.push(TaskFailure {
+                        at: parse_timestamp(at)?,
+                        reason: unescape(reason),
+                    });
+                }
+                "description" => task.description = unescape(value),
+                "title" => title = Some(unescape(value)),
+                _ => unreachable!("not in KEYS"),
+            }
+        } else {
+            words.push(token.to_string());
+        }
+    }
+    task.title = title.unwrap_or_else(|| words.join(" "));
+    Ok(task)
+}
+
+fn known_pair(token: &str) -> Option<(&str, &str)> {
+    token
+        .split_once(':')
+        .filter(|(key, value)| KEYS.contains(key) && !value.is_empty())
+}
+
+// words of the title that would be read back as something else get a
+// leading backslash, the first one also must not pass for `x`, a priority
+// or a date
+fn escape_word(word: &str, first: bool) -> String {
+    let special = word.starts_with('\\')
+        || (word.len() > 1 && (word.starts_with('+') || word.starts_with('@')))
+        || known_pair(word).is_some()
+        || (first && (word == "x" || parse_priority(word).is_some() || parse_date(word).is_some()));
+    if special {
+        format!("\\{word}")
+    } else {
+        word.to_string()
+    }
+}
+
+// percent-encodes `%` and whitespace, which would end the value
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if c == '%' || c.is_whitespace() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("%{byte:02X}"));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// lenient, a `%` that does not start an escape is taken as it is, as in a
+// hand written `+50%off`
+fn unescape(value: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) if byte == b'%' => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).unwrap_or_else(|_| value.to_string())
+}
+
+fn priority_name(priority: u8) -> String {
+    if priority < 26 {
+        ((b'A' + priority) as char).to_string()
+    } else {
+        priority.to_string()
+    }
+}
+
+fn parse_priority(token: &str) -> Option<u8> {
+    match token.as_bytes() {
+        [b'(', letter @ b'A'..=b'Z', b')'] => Some(letter - b'A'),
+        _ => None,
+    }
+}
+
+fn parse_priority_name(value: &str) -> Result<u8, String> {
+    match value.as_bytes() {
+        [letter @ b'A'..=b'Z'] => Ok(letter - b'A'),
+        _ => value
+            .parse()
+            .map_err(|_| format!("invalid priority `{value}`")),
+    }
+}
+
+fn parse_status(value: &str) -> Result<TaskStatus, String> {
+    match value {
+        "open" => Ok(TaskStatus::Open),
+        "in-progress" => Ok(TaskStatus::InProgress),
+        "done" => Ok(TaskStatus::Done),
+        "cancelled" => Ok(TaskStatus::Cancelled),
+        _ => Err(format!("invalid status `{value}`")),
+    }
+}
+
+fn date(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m-%d").to_string()
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn date_or_timestamp(at: DateTime<Utc>) -> String {
+    if is_midnight(at) {
+        date(at)
+    } else {
+        timestamp(at)
+    }
+}
+
+fn parse_date(token: &str) -> Option<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(token, "%Y-%m-%d").ok()?;
+    Some(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0)?))
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|at| at.with_timezone(&Utc))
+        .map_err(|_| format!("invalid timestamp `{value}`"))
+}
+
+fn parse_date_or_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(value).map_or_else(|| parse_timestamp(value), Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(title: &str) -> Task {
+        Task {
+            id: 7,
+            title: title.to_string(),
+            ..Task::default()
+        }
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    // `Task` has no `PartialEq`, its JSON is what has to survive anyway
+    fn assert_round_trip(task: Task) {
+        let line = to_line(&task);
+        let parsed = parse_line(&line).unwrap_or_else(|err| panic!("{line}: {err}"));
+        let expected = Task { id: 0, ..task };
+        assert_eq!(
+            serde_json::to_value(&parsed).unwrap(),
+            serde_json::to_value(&expected).unwrap(),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn plain_task() {
+        assert_round_trip(task("Call mom"));
+        assert_round_trip(Task {
+            priority: Some(0),
+            created_at: Some(at(1, 0)),
+            due_at: Some(at(5, 0)),
+            ..task("Call mom")
+        });
+    }
+
+    #[test]
+    fn done_tasks() {
+        for status in [TaskStatus::Done, TaskStatus::Cancelled] {
+            assert_round_trip(Task {
+                status,
+                ..task("Book venue")
+            });
+            assert_round_trip(Task {
+                status,
+                completed_at: Some(at(2, 0)),
+                ..task("Book venue")
+            });
+            assert_round_trip(Task {
+                status,
+                created_at: Some(at(1, 0)),
+                ..task("Book venue")
+            });
+            assert_round_trip(Task {
+                status,
+                priority: Some(3),
+                created_at: Some(at(1, 9)),
+                completed_at: Some(at(2, 17)),
+                ..task("Book venue")
+            });
+        }
+    }
+
+    #[test]
+    fn in_progress_task() {
+        assert_round_trip(Task {
+            status: TaskStatus::InProgress,
+            completed_at: Some(at(2, 0)),
+            ..task("Write docs")
+        });
+    }
+
+    #[test]
+    fn priority_beyond_z() {
+        assert_round_trip(Task {
+            priority: Some(26),
+            ..task("Someday")
+        });
+        assert_round_trip(Task {
+            priority: Some(255),
+            status: TaskStatus::Done,
+            ..task("Someday")
+        });
+    }
+
+    #[test]
+    fn titles_that_look_like_something_else() {
+        for title in [
+            "x marks the spot",
+            "(A) is a grade",
+            "2024-01-01 was a Monday",
+            "due:x is a word",
+            "+1 and @you",
+            "\\ back slash",
+            "x",
+            "",
+        ] {
+            assert_round_trip(task(title));
+            assert_round_trip(Task {
+                status: TaskStatus::Done,
+                completed_at: Some(at(2, 0)),
+                ..task(title)
+            });
+        }
+    }
+
+    #[test]
+    fn whitespace_in_titles() {
+        assert_round_trip(task("two  spaces"));
+        assert_round_trip(task(" leading and trailing "));
+        assert_round_trip(task("tab\there"));
+    }
+
+    #[test]
+    fn tags() {
+        assert_round_trip(Task {
+            tags: vec![
+                "work".to_string(),
+                "@phone".to_string(),
+                "with space".to_string(),
+                "50%".to_string(),
+                "+plus".to_string(),
+                "@".to_string(),
+            ],
+            ..task("Tagged")
+        });
+    }
+
+    #[test]
+    fn everything_else() {
+        assert_round_trip(Task {
+            description: "first line\nsecond line with 100% and\ttab\n".to_string(),
+            due_at: Some(at(5, 12)),
+            created_at: Some(Utc.timestamp_opt(1_700_000_000, 123_456_789).unwrap()),
+            updated_at: Some(at(3, 8)),
+            attempts: 2,
+            failures: vec![TaskFailure {
+                at: at(3, 7),
+                reason: "lease expired / retry".to_string(),
+            }],
+            ..task("Busy")
+        });
+    }
+
+    #[test]
+    fn hand_written_lines() {
+        let task = parse_line("x (A) 2024-03-02 2024-03-01 Call mom +family @phone due:2024-03-05")
+            .unwrap();
+        assert!(matches!(task.status, TaskStatus::Done));
+        assert_eq!(task.priority, Some(0));
+        assert_eq!(task.completed_at, Some(at(2, 0)));
+        assert_eq!(task.created_at, Some(at(1, 0)));
+        assert_eq!(task.title, "Call mom");
+        assert_eq!(task.tags, ["family", "@phone"]);
+        assert_eq!(task.due_at, Some(at(5, 0)));
+
+        let task = parse_line("Buy milk t:2024-03-01 +50%off").unwrap();
+        assert_eq!(task.title, "Buy milk t:2024-03-01");
+        assert_eq!(task.tags, ["50%off"]);
+
+        assert!(parse_line("Pay rent due:tomorrow").is_err());
+    }
+}
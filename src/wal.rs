@@ -0,0 +1,235 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    marker::PhantomData,
+    path::PathBuf,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogRecord<T> {
+    pub seq: u64,
+    pub op: T,
+}
+
+/// Append-only log of operations, one JSON record per line.
+///
+/// Every record carries a sequence number. Snapshots remember the last
+/// sequence number they contain, so records that were already folded into a
+/// snapshot are skipped on replay even if the log was not truncated yet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WriteAheadLog<T> {
+    path: PathBuf,
+    next_seq: u64,
+    #[serde(skip)]
+    _op: PhantomData<T>,
+}
+
+impl<T> WriteAheadLog<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            // 0 is what an empty snapshot claims to contain
+            next_seq: 1,
+            _op: PhantomData,
+        }
+    }
+
+    /// Reads back every record that is newer than `after`.
+    ///
+    /// A record at the very end of the file that cannot be parsed or lacks
+    /// its newline is the leftover of a write that got cut short. It was
+    /// never acknowledged, so it is dropped. Garbage anywhere else means the
+    /// log is corrupt.
+    pub fn replay(&mut self, after: u64) -> io::Result<Vec<LogRecord<T>>> {
+        self.next_seq = self.next_seq.max(after + 1);
+        // bytes, not a string: the cut may have gone through a character
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut lines = Vec::new();
+        let mut start = 0;
+        for line in content.split(|&byte| byte == b'\n') {
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                lines.push((start, line));
+            }
+            start += line.len() + 1;
+        }
+        let mut records = Vec::new();
+        for (i, &(start, line)) in lines.iter().enumerate() {
+            let last = i + 1 == lines.len();
+            let terminated = start + line.len() < content.len();
+            let record: LogRecord<T> = match serde_json::from_slice(line) {
+                Ok(record) if terminated => record,
+                Err(err) if !last => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                _ => {
+                    // cut it off, or the next append would continue its line
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&self.path)?
+                        .set_len(start as u64)?;
+                    break;
+                }
+            };
+            self.next_seq = self.next_seq.max(record.seq + 1);
+            if record.seq > after {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Durably appends `op` and returns its sequence number. Only once this
+    /// returns may the operation be applied and acknowledged.
+    pub fn append(&mut self, op: &T) -> io::Result<u64> {
+        let record = LogRecord {
+            seq: self.next_seq,
+            op,
+        };
+        let mut line = serde_json::to_vec(&record)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        self.next_seq += 1;
+        Ok(record.seq)
+    }
+
+    /// Sequence number of the last appended record.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(1)
+    }
+
+    /// Drops every record. Only call this after a snapshot containing them
+    /// was written.
+    pub fn truncate(&mut self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("wal-{}-{name}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn ops(records: &[LogRecord<String>]) -> Vec<(u64, &str)> {
+        records
+            .iter()
+            .map(|record| (record.seq, record.op.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn replays_what_was_appended() {
+        let path = log_path("replay");
+        let mut log = WriteAheadLog::new(path.clone());
+        assert_eq!(log.append(&"a".to_string()).unwrap(), 1);
+        assert_eq!(log.append(&"b".to_string()).unwrap(), 2);
+
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a"), (2, "b")]);
+        assert_eq!(log.last_seq(), 2);
+        // records a snapshot already contains are skipped
+        assert_eq!(ops(&log.replay(1).unwrap()), [(2, "b")]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn drops_a_torn_last_record() {
+        let path = log_path("torn");
+        let mut log = WriteAheadLog::new(path.clone());
+        log.append(&"a".to_string()).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":2,"op":"b"#).unwrap();
+
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a")]);
+        // the torn record never happened, its sequence number is free again
+        assert_eq!(log.last_seq(), 1);
+
+        assert_eq!(log.append(&"c".to_string()).unwrap(), 2);
+        log.append(&"d".to_string()).unwrap();
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a"), (2, "c"), (3, "d")]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn drops_a_record_torn_inside_a_character() {
+        let path = log_path("torn-char");
+        let mut log = WriteAheadLog::new(path.clone());
+        log.append(&"a".to_string()).unwrap();
+        let record = "{\"seq\":2,\"op\":\"é\"}\n".as_bytes();
+        let torn_at = record.iter().position(|&byte| byte == 0xc3).unwrap() + 1;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..torn_at]).unwrap();
+
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a")]);
+        assert_eq!(log.append(&"b".to_string()).unwrap(), 2);
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a"), (2, "b")]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn drops_a_last_record_without_its_newline() {
+        let path = log_path("unterminated");
+        let mut log = WriteAheadLog::new(path.clone());
+        log.append(&"a".to_string()).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":2,"op":"b"}"#).unwrap();
+
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a")]);
+        assert_eq!(log.append(&"c".to_string()).unwrap(), 2);
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert_eq!(ops(&log.replay(0).unwrap()), [(1, "a"), (2, "c")]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_garbage_before_the_end() {
+        let path = log_path("corrupt");
+        fs::write(
+            &path,
+            "{\"seq\":1,\"op\":\"a\"}\nnot json\n{\"seq\":3,\"op\":\"c\"}\n",
+        )
+        .unwrap();
+
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        let err = log.replay(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn continues_after_the_snapshot() {
+        let path = log_path("snapshot");
+        let mut log = WriteAheadLog::<String>::new(path.clone());
+        assert!(log.replay(5).unwrap().is_empty());
+        assert_eq!(log.append(&"f".to_string()).unwrap(), 6);
+        fs::remove_file(path).unwrap();
+    }
+}